@@ -1,14 +1,60 @@
 use base64;
+use error::{GrappleError, Result};
 use md5;
 use reqwest::header::Headers;
-use reqwest::Client;
+use sha2::{Digest, Sha256, Sha512Trunc256};
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard};
 use url::Url;
 use uuid::Uuid;
 
 lazy_static! {
     static ref NONCES: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+    static ref CHALLENGES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// The last authentication challenge seen from the origin of `url`, if any.
+pub fn cached_challenge(url: &Url) -> Option<String> {
+    lock_challenges()
+        .get(&url.origin().ascii_serialization())
+        .cloned()
+}
+
+/// Remembers the challenge from a 401 so later requests to the same origin can authenticate up
+/// front instead of being rejected first.
+pub fn cache_challenge(url: &Url, challenge: String) {
+    lock_challenges().insert(url.origin().ascii_serialization(), challenge);
+}
+
+/// The strongest supported challenge from the `WWW-Authenticate` headers of a response. Digest
+/// is preferred over Basic so the password isn't sent in the clear, taking the first Digest
+/// challenge that can be answered as the server lists them in order of preference.
+pub fn select_challenge(headers: &Headers) -> Option<String> {
+    let challenges: Vec<String> = headers
+        .get_raw("WWW-Authenticate")?
+        .iter()
+        .filter_map(|value| String::from_utf8(value.to_vec()).ok())
+        .collect();
+
+    challenges
+        .iter()
+        .find(|challenge| {
+            challenge.starts_with("Digest ")
+                && DigestChallenge::parse(&challenge["Digest ".len()..]).is_ok()
+        })
+        .or_else(|| {
+            challenges
+                .iter()
+                .find(|challenge| challenge.starts_with("Basic "))
+        })
+        .or_else(|| challenges.first())
+        .cloned()
+}
+
+fn lock_challenges() -> MutexGuard<'static, HashMap<String, String>> {
+    CHALLENGES
+        .lock()
+        .expect("Failed to acquire CHALLENGES lock, lock poisoned!")
 }
 
 pub struct AuthenticationRequest {
@@ -33,26 +79,29 @@ impl AuthenticationRequest {
         }
     }
 
-    pub fn authenticate(&self) -> Result<Option<Headers>, &'static str> {
+    /// Builds the `Authorization` header answering a `WWW-Authenticate` challenge.
+    pub fn authenticate(&self, challenge: &str) -> Result<Headers> {
         let basic_auth = "Basic".to_string();
         let digest_auth = "Digest".to_string();
 
-        match self.requires_authentication() {
-            Ok(Some(header_value)) => {
-                if let Some((auth_type, rest)) = self.authentication_type(&header_value) {
-                    if auth_type == basic_auth {
-                        self.do_basic_auth()
-                    } else if auth_type == digest_auth {
-                        self.do_digest_auth(&rest)
-                    } else {
-                        Err("Authentication type is not supported yet.")
-                    }
-                } else {
-                    Err("Incorrect WWW-Authenticate header.")
-                }
+        if let Some((auth_type, rest)) = self.authentication_type(challenge) {
+            if auth_type == basic_auth {
+                self.do_basic_auth()
+            } else if auth_type == digest_auth {
+                self.do_digest_auth(&rest)
+            } else if auth_type == "Bearer" {
+                Err(GrappleError::Auth(
+                    "The server requires a bearer token, pass one with --bearer.".to_string(),
+                ))
+            } else {
+                Err(GrappleError::Auth(
+                    "Authentication type is not supported yet.".to_string(),
+                ))
             }
-            Ok(None) => Ok(None),
-            Err(message) => Err(message),
+        } else {
+            Err(GrappleError::Auth(
+                "Incorrect WWW-Authenticate header.".to_string(),
+            ))
         }
     }
 
@@ -65,7 +114,7 @@ impl AuthenticationRequest {
         })
     }
 
-    fn do_basic_auth(&self) -> Result<Option<Headers>, &'static str> {
+    fn do_basic_auth(&self) -> Result<Headers> {
         let mut data = self.username.clone();
         data.push(':');
         if let Some(ref pass) = self.password {
@@ -74,148 +123,554 @@ impl AuthenticationRequest {
         let header = format!("Basic {}", base64::encode(&data));
         let mut headers = Headers::new();
         headers.set_raw("Authorization", vec![header.into_bytes().to_vec()]);
-        Ok(Some(headers))
-    }
-
-    fn do_digest_auth(&self, header_value: &str) -> Result<Option<Headers>, &'static str> {
-        if self.method.is_none() {
-            return Err("Method required for digest authentication.");
-        }
-
-        let uri = self.get_request_path();
-        let trimmed = header_value.trim().to_string();
-        let collected = trimmed.split(',').map(|s| s.trim()).collect();
-        let cnonce = Uuid::new_v4().to_string();
-
-        let password = self.password.clone().unwrap_or_else(|| "".to_string());
-        let method = self.method.clone().unwrap();
-        let realm = self
-            .find_string_value(&collected, "realm")
-            .unwrap_or_else(|| "".to_string());
-        let nonce = self
-            .find_string_value(&collected, "nonce")
-            .unwrap_or_else(|| "".to_string());
-        let qop = self
-            .find_string_value(&collected, "qop")
-            .unwrap_or_else(|| "".to_string());
-
-        let mut nonces = NONCES
-            .lock()
-            .expect("Failed to acquire NONCES lock, lock poisoned!");
-        let nc = nonces.entry(nonce.clone()).or_insert(1);
-
-        let ha1 = md5::compute(format!("{}:{}:{}", self.username.clone(), realm, password));
-        let ha2 = md5::compute(format!("{}:{}", method, uri));
-        let response = md5::compute(format!(
-            "{:x}:{}:{:0>8}:{}:{}:{:x}",
-            ha1, nonce, nc, cnonce, qop, ha2
-        ));
-        let auth_header = format!(
-            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", \
-             uri=\"{}\", qop={}, nc={:0>8}, cnonce=\"{}\", \
-             response=\"{:x}\"",
-            self.username, realm, nonce, uri, qop, nc, cnonce, response
-        );
+        Ok(headers)
+    }
+
+    fn do_digest_auth(&self, header_value: &str) -> Result<Headers> {
+        let method = match self.method {
+            Some(ref method) => method.clone(),
+            None => {
+                return Err(GrappleError::Auth(
+                    "Method required for digest authentication.".to_string(),
+                ))
+            }
+        };
+
+        let challenge = DigestChallenge::parse(header_value)?;
+        let nc = {
+            let mut nonces = NONCES
+                .lock()
+                .expect("Failed to acquire NONCES lock, lock poisoned!");
+            let nc = nonces.entry(challenge.nonce.clone()).or_insert(0);
+            *nc += 1;
+            *nc
+        };
+
+        let credentials = DigestCredentials {
+            username: &self.username,
+            password: self
+                .password
+                .as_ref()
+                .map_or("", |password| password.as_str()),
+            method: &method,
+            uri: &self.get_request_path()?,
+            cnonce: &Uuid::new_v4().to_string(),
+            nc,
+        };
+        let auth_header = challenge.respond(&credentials);
 
         let mut headers = Headers::new();
         headers.set_raw("Authorization", vec![auth_header.into_bytes().to_vec()]);
-        Ok(Some(headers))
+        Ok(headers)
     }
 
-    fn get_request_path(&self) -> String {
-        let parsed_url = Url::parse(&self.url).unwrap();
+    fn get_request_path(&self) -> Result<String> {
+        let parsed_url = Url::parse(&self.url)
+            .map_err(|e| GrappleError::InvalidOption(format!("Couldn't parse URI: {}", e)))?;
         let path = parsed_url.path();
-        match parsed_url.query() {
+        Ok(match parsed_url.query() {
             Some(qs) => format!("{}?{}", path, qs),
             None => path.to_string(),
+        })
+    }
+}
+
+/// Hash algorithms for Digest authentication, RFC 7616 section 6.1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DigestAlgorithm {
+    Md5,
+    Sha256,
+    Sha512_256,
+}
+
+/// A parsed `WWW-Authenticate: Digest` challenge.
+#[derive(Clone, Debug, PartialEq)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: DigestAlgorithm,
+    /// `-sess` variant, where HA1 also covers the nonce and cnonce.
+    session: bool,
+    /// Quality of protection to answer with, `None` for RFC 2069 style challenges.
+    qop: Option<&'static str>,
+    stale: bool,
+    userhash: bool,
+}
+
+/// Everything about the request a digest response covers.
+struct DigestCredentials<'a> {
+    username: &'a str,
+    password: &'a str,
+    method: &'a str,
+    uri: &'a str,
+    cnonce: &'a str,
+    nc: usize,
+}
+
+impl DigestAlgorithm {
+    fn hash(self, data: &str) -> String {
+        match self {
+            DigestAlgorithm::Md5 => format!("{:x}", md5::compute(data)),
+            DigestAlgorithm::Sha256 => to_hex(&Sha256::digest(data.as_bytes())),
+            DigestAlgorithm::Sha512_256 => to_hex(&Sha512Trunc256::digest(data.as_bytes())),
         }
     }
+}
+
+impl DigestChallenge {
+    fn parse(header_value: &str) -> Result<DigestChallenge> {
+        let params = parse_auth_params(header_value)
+            .ok_or_else(|| GrappleError::Auth("Incorrect WWW-Authenticate header.".to_string()))?;
+        let param = |name: &str| params.get(name).cloned();
+
+        let nonce = param("nonce").ok_or_else(|| {
+            GrappleError::Auth("Digest challenge is missing a nonce.".to_string())
+        })?;
 
-    fn find_string_value(&self, parts: &Vec<&str>, field: &str) -> Option<String> {
-        for p in parts {
-            if p.starts_with(&field) {
-                let formatted = format!("{}=", field);
-                return Some(self.unquote(&p.replace(&formatted, "")));
+        let algorithm_name = param("algorithm").unwrap_or_else(|| "MD5".to_string());
+        let upper_name = algorithm_name.to_uppercase();
+        let (base_name, session) = if upper_name.ends_with("-SESS") {
+            (&upper_name[..upper_name.len() - 5], true)
+        } else {
+            (upper_name.as_str(), false)
+        };
+        let algorithm = match base_name {
+            "MD5" => DigestAlgorithm::Md5,
+            "SHA-256" => DigestAlgorithm::Sha256,
+            "SHA-512-256" => DigestAlgorithm::Sha512_256,
+            _ => {
+                return Err(GrappleError::Auth(format!(
+                    "Digest algorithm {} is not supported.",
+                    algorithm_name
+                )))
             }
-        }
-        None
+        };
+
+        // Prefer plain auth, as auth-int has to hash the request body
+        let qop = match param("qop") {
+            None => None,
+            Some(options) => {
+                let options: Vec<String> = options
+                    .split(',')
+                    .map(|option| option.trim().to_lowercase())
+                    .collect();
+                if options.iter().any(|option| option == "auth") {
+                    Some("auth")
+                } else if options.iter().any(|option| option == "auth-int") {
+                    Some("auth-int")
+                } else {
+                    return Err(GrappleError::Auth(format!(
+                        "Digest qop {} is not supported.",
+                        options.join(", ")
+                    )));
+                }
+            }
+        };
+
+        let is_true =
+            |name: &str| param(name).map_or(false, |value| value.eq_ignore_ascii_case("true"));
+
+        Ok(DigestChallenge {
+            realm: param("realm").unwrap_or_default(),
+            nonce,
+            opaque: param("opaque"),
+            algorithm,
+            session,
+            qop,
+            stale: is_true("stale"),
+            userhash: is_true("userhash"),
+        })
     }
 
-    fn unquote(&self, input: &str) -> String {
-        let mod1 = input.trim_left_matches('\"').to_string();
-        mod1.trim_right_matches('\"').to_string()
+    /// The `Authorization` header value answering this challenge.
+    fn respond(&self, credentials: &DigestCredentials) -> String {
+        let h = |data: &str| self.algorithm.hash(data);
+        let nc = format!("{:08x}", credentials.nc);
+
+        let mut ha1 = h(&format!(
+            "{}:{}:{}",
+            credentials.username, self.realm, credentials.password
+        ));
+        if self.session {
+            ha1 = h(&format!("{}:{}:{}", ha1, self.nonce, credentials.cnonce));
+        }
+
+        // Only GET and HEAD requests are sent, so auth-int always covers an empty body
+        let ha2 = match self.qop {
+            Some("auth-int") => h(&format!(
+                "{}:{}:{}",
+                credentials.method,
+                credentials.uri,
+                h("")
+            )),
+            _ => h(&format!("{}:{}", credentials.method, credentials.uri)),
+        };
+
+        let response = match self.qop {
+            Some(qop) => h(&format!(
+                "{}:{}:{}:{}:{}:{}",
+                ha1, self.nonce, nc, credentials.cnonce, qop, ha2
+            )),
+            None => h(&format!("{}:{}:{}", ha1, self.nonce, ha2)),
+        };
+
+        let username = if self.userhash {
+            h(&format!("{}:{}", credentials.username, self.realm))
+        } else {
+            credentials.username.to_string()
+        };
+
+        let mut header = format!(
+            "Digest username={}, realm={}, nonce={}, uri={}, algorithm={}{}",
+            quote(&username),
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(credentials.uri),
+            match self.algorithm {
+                DigestAlgorithm::Md5 => "MD5",
+                DigestAlgorithm::Sha256 => "SHA-256",
+                DigestAlgorithm::Sha512_256 => "SHA-512-256",
+            },
+            if self.session { "-sess" } else { "" }
+        );
+        if let Some(qop) = self.qop {
+            header.push_str(&format!(
+                ", qop={}, nc={}, cnonce={}",
+                qop,
+                nc,
+                quote(credentials.cnonce)
+            ));
+        }
+        header.push_str(&format!(", response={}", quote(&response)));
+        if let Some(ref opaque) = self.opaque {
+            header.push_str(&format!(", opaque={}", quote(opaque)));
+        }
+        if self.userhash {
+            header.push_str(", userhash=true");
+        }
+        header
     }
+}
+
+/// Whether a challenge says the previous nonce expired, rather than the credentials being
+/// wrong, RFC 7616 section 3.3.
+pub fn is_stale(challenge: &str) -> bool {
+    challenge.starts_with("Digest ")
+        && DigestChallenge::parse(&challenge["Digest ".len()..])
+            .map(|challenge| challenge.stale)
+            .unwrap_or(false)
+}
+
+/// Parses the comma separated `name=value` pairs of a challenge, where values are tokens or
+/// quoted strings which may contain commas and escaped quotes. Names are lower cased.
+fn parse_auth_params(input: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut chars = input.chars().peekable();
 
-    fn requires_authentication(&self) -> Result<Option<String>, &'static str> {
-        let client = Client::new();
-        match client.head(&self.url).send() {
-            Ok(ref mut res) => {
-                match res.headers().get_raw("WWW-Authenticate") {
-                    Some(raw) => {
-                        // debug!("WWW-Authenticate is: {}",
-                        //    String::from_utf8(raw.get(0).unwrap().clone()).unwrap());
-                        Ok(Some(
-                            String::from_utf8(raw.one().unwrap().to_vec()).unwrap(),
-                        ))
-                    }
-                    None => Ok(None),
+    loop {
+        while chars
+            .peek()
+            .map_or(false, |&c| c == ',' || c.is_whitespace())
+        {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Some(params);
+        }
+
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ',' || c.is_whitespace() {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.next() != Some('=') {
+            return None;
+        }
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    '\\' => value.push(chars.next()?),
+                    '"' => break,
+                    c => value.push(c),
                 }
             }
-            Err(_) => {
-                // debug!("Error while issuing HEAD request. Reason: {}",
-                //    e.to_string());
-                Err("Could not issue a HTTP request.")
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c.is_whitespace() {
+                    break;
+                }
+                value.push(c);
+                chars.next();
             }
         }
+
+        params.insert(name.to_lowercase(), value);
     }
 }
 
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Lowercase hex encoding of a digest.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
 
-    #[test]
-    fn test_wrong_url_error() {
-        let url = "http://httpbin.orgmeh/digest-auth/auth/user/passwd".to_string();
+    const DIGEST_CHALLENGE: &str = "Digest realm=\"me@kennethreitz.com\", \
+                                    nonce=\"1d5e2ed2cb5b6b2d4a0b5c1d1e2f3a4b\", qop=\"auth\"";
+
+    fn request(method: Option<&str>) -> AuthenticationRequest {
+        let url = "http://httpbin.org/digest-auth/auth/user/passwd".to_string();
         let username = "user".to_string();
         let password = Some("passwd".to_string());
-        let method = None;
-        let ar = AuthenticationRequest::new(url, username, password, method);
-        let result = ar.authenticate();
-        assert_eq!(result.is_err(), true);
+        AuthenticationRequest::new(url, username, password, method.map(|m| m.to_string()))
+    }
+
+    fn authorization(headers: &Headers) -> String {
+        String::from_utf8(
+            headers
+                .get_raw("Authorization")
+                .unwrap()
+                .one()
+                .unwrap()
+                .to_vec(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_wrong_header_error() {
+        assert_eq!(request(None).authenticate("Basic").is_err(), true);
+        assert_eq!(request(None).authenticate("Negotiate abc").is_err(), true);
     }
 
     #[test]
     fn test_digest_auth_no_method() {
-        let url = "http://httpbin.org/digest-auth/auth/user/passwd".to_string();
-        let username = "user".to_string();
-        let password = Some("passwd".to_string());
-        let method = None;
-        let ar = AuthenticationRequest::new(url, username, password, method);
-        let result = ar.authenticate();
+        let result = request(None).authenticate(DIGEST_CHALLENGE);
         assert_eq!(result.is_err(), true);
     }
 
     #[test]
     fn test_basic_auth_works() {
-        let url = "http://httpbin.org/basic-auth/user/passwd".to_string();
-        let username = "user".to_string();
-        let password = Some("passwd".to_string());
-        let method = None;
-        let ar = AuthenticationRequest::new(url, username, password, method);
-        let result = ar.authenticate();
-        assert_eq!(result.is_ok(), true);
+        let result = request(None).authenticate("Basic realm=\"Fake Realm\"");
+        assert_eq!(authorization(&result.unwrap()), "Basic dXNlcjpwYXNzd2Q=");
     }
 
     #[test]
     fn test_digest_auth_works() {
-        let url = "http://httpbin.org/digest-auth/auth/user/passwd".to_string();
-        let username = "user".to_string();
-        let password = Some("passwd".to_string());
-        let method = Some("POST".to_string());
-        let ar = AuthenticationRequest::new(url, username, password, method);
-        let result = ar.authenticate();
-        assert_eq!(result.is_ok(), true);
+        let result = request(Some("POST")).authenticate(DIGEST_CHALLENGE);
+        assert!(authorization(&result.unwrap()).starts_with("Digest username=\"user\""));
+    }
+
+    #[test]
+    fn test_digest_nonce_count_increments() {
+        let challenge = "Digest realm=\"r\", nonce=\"nc-test\", qop=\"auth\"";
+        let first = authorization(&request(Some("GET")).authenticate(challenge).unwrap());
+        let second = authorization(&request(Some("GET")).authenticate(challenge).unwrap());
+        assert!(first.contains("nc=00000001"));
+        assert!(second.contains("nc=00000002"));
+    }
+
+    #[test]
+    fn test_select_challenge_prefers_supported() {
+        let mut headers = Headers::new();
+        headers.append_raw("WWW-Authenticate", "Negotiate");
+        headers.append_raw("WWW-Authenticate", "Basic realm=\"r\"");
+        assert_eq!(
+            select_challenge(&headers),
+            Some("Basic realm=\"r\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_select_challenge_prefers_digest() {
+        let mut headers = Headers::new();
+        headers.append_raw("WWW-Authenticate", "Basic realm=\"r\"");
+        headers.append_raw(
+            "WWW-Authenticate",
+            "Digest realm=\"r\", nonce=\"n\", algorithm=SHA3-256",
+        );
+        headers.append_raw(
+            "WWW-Authenticate",
+            "Digest realm=\"r\", nonce=\"n\", algorithm=SHA-256",
+        );
+        headers.append_raw("WWW-Authenticate", "Digest realm=\"r\", nonce=\"n\"");
+        assert_eq!(
+            select_challenge(&headers),
+            Some("Digest realm=\"r\", nonce=\"n\", algorithm=SHA-256".to_string())
+        );
+
+        let mut headers = Headers::new();
+        headers.append_raw("WWW-Authenticate", "Basic realm=\"r\"");
+        headers.append_raw(
+            "WWW-Authenticate",
+            "Digest realm=\"r\", nonce=\"n\", algorithm=SHA3-256",
+        );
+        assert_eq!(
+            select_challenge(&headers),
+            Some("Basic realm=\"r\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_challenge_cache_per_origin() {
+        let url = Url::parse("http://cache-test.example.com/a/file").unwrap();
+        let other = Url::parse("http://cache-test.example.com:8080/a/file").unwrap();
+        cache_challenge(&url, "Basic realm=\"r\"".to_string());
+        let same_origin = Url::parse("http://cache-test.example.com/b").unwrap();
+        assert_eq!(
+            cached_challenge(&same_origin),
+            Some("Basic realm=\"r\"".to_string())
+        );
+        assert_eq!(cached_challenge(&other), None);
+    }
+
+    fn rfc_7616_credentials() -> DigestCredentials<'static> {
+        DigestCredentials {
+            username: "Mufasa",
+            password: "Circle of Life",
+            method: "GET",
+            uri: "/dir/index.html",
+            cnonce: "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+            nc: 1,
+        }
+    }
+
+    fn rfc_7616_challenge(algorithm: &str) -> DigestChallenge {
+        DigestChallenge::parse(&format!(
+            "realm=\"http-auth@example.org\", qop=\"auth, auth-int\", algorithm={}, \
+             nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+             opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"",
+            algorithm
+        ))
+        .unwrap()
+    }
+
+    fn response_params(header: &str) -> HashMap<String, String> {
+        assert!(header.starts_with("Digest "));
+        parse_auth_params(&header["Digest ".len()..]).unwrap()
+    }
+
+    #[test]
+    fn test_parse_auth_params_quoting() {
+        let params = parse_auth_params(
+            "realm=\"a, \\\"b\\\"\", qop=\"auth,auth-int\" , nonce=abc,opaque=\"\"",
+        )
+        .unwrap();
+        assert_eq!(params["realm"], "a, \"b\"");
+        assert_eq!(params["qop"], "auth,auth-int");
+        assert_eq!(params["nonce"], "abc");
+        assert_eq!(params["opaque"], "");
+        assert_eq!(parse_auth_params("realm=\"unterminated"), None);
+    }
+
+    #[test]
+    fn test_parse_challenge_fields() {
+        let challenge = DigestChallenge::parse(
+            "realm=\"r\", nonceprefix=\"x\", nonce=\"n\", algorithm=SHA-256-sess, \
+             qop=\"auth-int\", stale=TRUE",
+        )
+        .unwrap();
+        assert_eq!(challenge.nonce, "n");
+        assert_eq!(challenge.algorithm, DigestAlgorithm::Sha256);
+        assert!(challenge.session);
+        assert_eq!(challenge.qop, Some("auth-int"));
+        assert!(challenge.stale);
+        assert!(DigestChallenge::parse("realm=\"r\", nonce=\"n\", algorithm=SHA-1").is_err());
+    }
+
+    #[test]
+    fn test_rfc_2617_md5_vector() {
+        let challenge = DigestChallenge::parse(
+            "realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        )
+        .unwrap();
+        let credentials = DigestCredentials {
+            password: "Circle Of Life",
+            cnonce: "0a4f113b",
+            ..rfc_7616_credentials()
+        };
+        let params = response_params(&challenge.respond(&credentials));
+        assert_eq!(params["response"], "6629fae49393a05397450978507c4ef1");
+        assert_eq!(params["opaque"], "5ccc069c403ebaf9f0171e9517f40e41");
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["qop"], "auth");
+    }
+
+    #[test]
+    fn test_rfc_7616_md5_vector() {
+        let header = rfc_7616_challenge("MD5").respond(&rfc_7616_credentials());
+        let params = response_params(&header);
+        assert_eq!(params["response"], "8ca523f5e9506fed4657c9700eebdbec");
+        assert_eq!(
+            params["opaque"],
+            "FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS"
+        );
+        assert_eq!(params["algorithm"], "MD5");
+    }
+
+    #[test]
+    fn test_rfc_7616_sha_256_vector() {
+        let header = rfc_7616_challenge("SHA-256").respond(&rfc_7616_credentials());
+        assert_eq!(
+            response_params(&header)["response"],
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+    }
+
+    #[test]
+    fn test_sha_512_256_and_sess() {
+        let header = rfc_7616_challenge("SHA-512-256").respond(&rfc_7616_credentials());
+        assert_eq!(
+            response_params(&header)["response"],
+            "430d05014cecc49cab6fbe03176d41a1da86cbfe24a16580e22aaad928d960d0"
+        );
+
+        let header = rfc_7616_challenge("MD5-sess").respond(&rfc_7616_credentials());
+        let params = response_params(&header);
+        assert_eq!(params["algorithm"], "MD5-sess");
+        assert_eq!(params["response"], "e783283f46242139c486a698fec7211d");
+    }
+
+    #[test]
+    fn test_auth_int_and_rfc_2069() {
+        let challenge = DigestChallenge::parse("realm=\"r\", nonce=\"n\", qop=\"auth-int\"");
+        let params = response_params(&challenge.unwrap().respond(&rfc_7616_credentials()));
+        assert_eq!(params["qop"], "auth-int");
+        assert_eq!(params["response"], "11605d06519bc3a32daa9767e5309354");
+
+        let challenge = DigestChallenge::parse("realm=\"r\", nonce=\"n\"").unwrap();
+        let params = response_params(&challenge.respond(&rfc_7616_credentials()));
+        assert!(!params.contains_key("qop"));
+        assert!(!params.contains_key("nc"));
+        assert_eq!(params["response"], "0bab5c24c08a1ef94a6cc3d446ce558a");
+    }
+
+    #[test]
+    fn test_is_stale() {
+        assert!(is_stale("Digest realm=\"r\", nonce=\"n\", stale=true"));
+        assert!(!is_stale("Digest realm=\"r\", nonce=\"n\""));
+        assert!(!is_stale("Basic realm=\"r\""));
     }
 }
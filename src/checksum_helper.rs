@@ -0,0 +1,355 @@
+use auth_helper::to_hex;
+use base64;
+use blake3;
+use error::{GrappleError, Result};
+use md5;
+use request_helper::raw_header_value;
+use reqwest::header::Headers;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Hash algorithms a finished download can be verified with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+/// Expected digest of a whole download.
+///
+/// Parsed from `<algorithm>:<hex>`, e.g. `sha256:e3b0c442...`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checksum {
+    algorithm: ChecksumAlgorithm,
+    digest: Vec<u8>,
+}
+
+impl ChecksumAlgorithm {
+    fn digest_len(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Md5 => 16,
+            ChecksumAlgorithm::Sha1 => 20,
+            ChecksumAlgorithm::Sha256 | ChecksumAlgorithm::Blake3 => 32,
+            ChecksumAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ChecksumAlgorithm::Md5 => "md5",
+            ChecksumAlgorithm::Sha1 => "sha1",
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Sha512 => "sha512",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = GrappleError;
+
+    fn from_str(name: &str) -> Result<ChecksumAlgorithm> {
+        match name.to_lowercase().as_str() {
+            "md5" => Ok(ChecksumAlgorithm::Md5),
+            "sha1" => Ok(ChecksumAlgorithm::Sha1),
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "sha512" => Ok(ChecksumAlgorithm::Sha512),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            _ => Err(GrappleError::InvalidOption(format!(
+                "Unknown checksum algorithm {}, expected one of md5, sha1, sha256, sha512 or \
+                 blake3.",
+                name
+            ))),
+        }
+    }
+}
+
+impl Checksum {
+    pub fn new(algorithm: ChecksumAlgorithm, digest: Vec<u8>) -> Result<Checksum> {
+        if digest.len() != algorithm.digest_len() {
+            return Err(GrappleError::InvalidOption(format!(
+                "A {} checksum must be {} bytes long.",
+                algorithm,
+                algorithm.digest_len()
+            )));
+        }
+
+        Ok(Checksum { algorithm, digest })
+    }
+
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+
+    /// The strongest digest of the whole file advertised in `Digest`, `x-amz-checksum-*` or
+    /// `Content-MD5` headers.
+    ///
+    /// Digests of an encoded body don't describe the decoded bytes written to disk, so nothing
+    /// is returned if the response has a `Content-Encoding`.
+    pub(crate) fn from_headers(headers: &Headers) -> Option<Checksum> {
+        if headers
+            .get_raw("Content-Encoding")
+            .and_then(|raw| raw.one())
+            .map_or(false, |encoding| encoding != b"identity")
+        {
+            return None;
+        }
+
+        let mut candidates = vec![];
+        if let Some(digests) = raw_header_value(headers, "Digest") {
+            for digest in digests.split(',') {
+                let mut parts = digest.trim().splitn(2, '=');
+                let name = parts.next().unwrap_or("").to_uppercase();
+                let algorithm = match name.as_str() {
+                    "MD5" => ChecksumAlgorithm::Md5,
+                    "SHA" => ChecksumAlgorithm::Sha1,
+                    "SHA-256" => ChecksumAlgorithm::Sha256,
+                    "SHA-512" => ChecksumAlgorithm::Sha512,
+                    _ => continue,
+                };
+                candidates.extend(parts.next().and_then(|value| from_base64(algorithm, value)));
+            }
+        }
+
+        let single_headers = [
+            ("x-amz-checksum-sha256", ChecksumAlgorithm::Sha256),
+            ("x-amz-checksum-sha1", ChecksumAlgorithm::Sha1),
+            ("Content-MD5", ChecksumAlgorithm::Md5),
+        ];
+        for &(name, algorithm) in &single_headers {
+            candidates.extend(
+                raw_header_value(headers, name).and_then(|value| from_base64(algorithm, &value)),
+            );
+        }
+
+        candidates
+            .into_iter()
+            .max_by_key(|checksum| checksum.algorithm.digest_len())
+    }
+
+    /// An S3 style ETag, which is the MD5 of the object unless it was uploaded in parts.
+    pub(crate) fn from_etag(etag: &str) -> Option<Checksum> {
+        if etag.len() != 34 || !etag.starts_with('"') || !etag.ends_with('"') {
+            return None;
+        }
+
+        from_hex(&etag[1..33]).and_then(|digest| Checksum::new(ChecksumAlgorithm::Md5, digest).ok())
+    }
+
+    /// Whether the first `len` bytes of `path` match the expected digest.
+    pub(crate) fn matches(&self, path: &Path, len: u64) -> Result<bool> {
+        Ok(self.digest_file(path, len)? == self.digest)
+    }
+
+    /// Hashes the first `len` bytes of `path` and compares them against the expected digest.
+    pub(crate) fn verify(&self, path: &Path, len: u64) -> Result<()> {
+        let actual = self.digest_file(path, len)?;
+        if actual != self.digest {
+            return Err(GrappleError::ChecksumMismatch(format!(
+                "expected {}:{} but got {}:{}, the partial file {} has been kept and is downloaded \
+                 again on the next run",
+                self.algorithm,
+                to_hex(&self.digest),
+                self.algorithm,
+                to_hex(&actual),
+                path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    fn digest_file(&self, path: &Path, len: u64) -> Result<Vec<u8>> {
+        let mut file = File::open(path)?.take(len);
+        let mut hasher = Hasher::new(self.algorithm);
+        let mut buf = vec![0; 128 * 1024];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+        Ok(hasher.finish())
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, to_hex(&self.digest))
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = GrappleError;
+
+    fn from_str(value: &str) -> Result<Checksum> {
+        let mut parts = value.splitn(2, ':');
+        let algorithm = parts.next().unwrap_or("").parse::<ChecksumAlgorithm>()?;
+        let digest = parts.next().and_then(from_hex).ok_or_else(|| {
+            GrappleError::InvalidOption(
+                "Checksum must be of the form <algorithm>:<hex digest>.".to_string(),
+            )
+        })?;
+        Checksum::new(algorithm, digest)
+    }
+}
+
+enum Hasher {
+    Md5(md5::Context),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(blake3::Hasher),
+}
+
+impl Hasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Hasher {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            ChecksumAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            ChecksumAlgorithm::Blake3 => Hasher::Blake3(blake3::Hasher::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match *self {
+            Hasher::Md5(ref mut context) => context.consume(data),
+            Hasher::Sha1(ref mut hasher) => hasher.input(data),
+            Hasher::Sha256(ref mut hasher) => hasher.input(data),
+            Hasher::Sha512(ref mut hasher) => hasher.input(data),
+            Hasher::Blake3(ref mut hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::Md5(context) => context.compute().0.to_vec(),
+            Hasher::Sha1(hasher) => hasher.result().to_vec(),
+            Hasher::Sha256(hasher) => hasher.result().to_vec(),
+            Hasher::Sha512(hasher) => hasher.result().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+fn from_base64(algorithm: ChecksumAlgorithm, value: &str) -> Option<Checksum> {
+    base64::decode(value.trim())
+        .ok()
+        .and_then(|digest| Checksum::new(algorithm, digest).ok())
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn parse_checksum() {
+        let checksum = format!("SHA256:{}", EMPTY_SHA256)
+            .parse::<Checksum>()
+            .unwrap();
+        assert_eq!(checksum.algorithm(), ChecksumAlgorithm::Sha256);
+        assert_eq!(checksum.to_string(), format!("sha256:{}", EMPTY_SHA256));
+    }
+
+    #[test]
+    fn parse_checksum_rejects_bad_input() {
+        assert!("sha256".parse::<Checksum>().is_err());
+        assert!("crc32:00000000".parse::<Checksum>().is_err());
+        assert!("md5:zz".parse::<Checksum>().is_err());
+        assert!(format!("md5:{}", EMPTY_SHA256).parse::<Checksum>().is_err());
+    }
+
+    #[test]
+    fn hasher_digests() {
+        let digest = |algorithm| {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"ab");
+            hasher.update(b"c");
+            to_hex(&hasher.finish())
+        };
+        assert_eq!(
+            digest(ChecksumAlgorithm::Md5),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+        assert_eq!(
+            digest(ChecksumAlgorithm::Sha1),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            digest(ChecksumAlgorithm::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest(ChecksumAlgorithm::Blake3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[test]
+    fn from_headers_prefers_strongest() {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-MD5", "kAFQmDzST7DWlj99KOF/cg==");
+        headers.set_raw(
+            "Digest",
+            "unixsum=30637, SHA-256=ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+        );
+        let checksum = Checksum::from_headers(&headers).unwrap();
+        assert_eq!(checksum.algorithm(), ChecksumAlgorithm::Sha256);
+        assert_eq!(
+            to_hex(&checksum.digest),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn from_etag_only_accepts_md5_etags() {
+        let checksum = Checksum::from_etag("\"900150983cd24fb0d6963f7d28e17f72\"").unwrap();
+        assert_eq!(checksum.algorithm(), ChecksumAlgorithm::Md5);
+        assert_eq!(
+            Checksum::from_etag("W/\"900150983cd24fb0d6963f7d28e17f72\""),
+            None
+        );
+        assert_eq!(
+            Checksum::from_etag("\"900150983cd24fb0d6963f7d28e17f72-2\""),
+            None
+        );
+        assert_eq!(Checksum::from_etag("\"abc\""), None);
+    }
+
+    #[test]
+    fn from_headers_ignores_encoded_bodies() {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-MD5", "kAFQmDzST7DWlj99KOF/cg==");
+        headers.set_raw("Content-Encoding", "gzip");
+        assert_eq!(Checksum::from_headers(&headers), None);
+    }
+}
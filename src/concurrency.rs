@@ -0,0 +1,91 @@
+use std::cmp;
+
+/// Connections an adaptive download starts with.
+const ADAPTIVE_START: usize = 2;
+
+/// How many workers the pool keeps busy, either fixed or tuned from throughput.
+#[derive(Debug)]
+pub struct Concurrency {
+    target: usize,
+    max: usize,
+    throttled: bool,
+}
+
+impl Concurrency {
+    /// Always `count` connections.
+    pub fn fixed(count: usize) -> Concurrency {
+        Concurrency {
+            target: count,
+            max: count,
+            throttled: false,
+        }
+    }
+
+    /// A few connections to start with, tuned up to `max` by `retune`.
+    pub fn adaptive(max: usize) -> Concurrency {
+        Concurrency {
+            target: cmp::min(ADAPTIVE_START, max),
+            max,
+            throttled: false,
+        }
+    }
+
+    pub fn target(&self) -> usize {
+        self.target
+    }
+
+    /// Records that the server pushed back with a 429 or 503.
+    pub fn throttled(&mut self) {
+        self.throttled = true;
+    }
+
+    /// Moves the target based on the bytes downloaded in the last two intervals, returning it.
+    pub fn retune(&mut self, previous: u64, current: u64) -> usize {
+        self.target = retune(self.target, self.max, previous, current, self.throttled);
+        self.throttled = false;
+        self.target
+    }
+}
+
+/// Halves the connections when the server pushes back, keeps adding them while throughput
+/// improves by more than a tenth and drops one when it falls by more than a fifth.
+fn retune(target: usize, max: usize, previous: u64, current: u64, throttled: bool) -> usize {
+    if throttled {
+        cmp::max(1, target / 2)
+    } else if current > previous + previous / 10 {
+        cmp::min(max, target + 1)
+    } else if current < previous - previous / 5 {
+        cmp::max(1, target - 1)
+    } else {
+        target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn retune_targets() {
+        assert_eq!(retune(2, 10, 0, 100, false), 3);
+        assert_eq!(retune(3, 10, 100, 105, false), 3);
+        assert_eq!(retune(3, 10, 100, 70, false), 2);
+        assert_eq!(retune(10, 10, 100, 200, false), 10);
+        assert_eq!(retune(8, 10, 100, 200, true), 4);
+        assert_eq!(retune(1, 10, 100, 0, true), 1);
+    }
+
+    #[test]
+    fn adaptive_starts_small_and_backs_off() {
+        let mut concurrency = Concurrency::adaptive(10);
+        assert_eq!(concurrency.target(), 2);
+        assert_eq!(concurrency.retune(0, 100), 3);
+        concurrency.throttled();
+        assert_eq!(concurrency.retune(100, 100), 1);
+        assert_eq!(concurrency.retune(100, 100), 1);
+
+        assert_eq!(Concurrency::adaptive(1).target(), 1);
+        assert_eq!(Concurrency::fixed(5).retune(0, 100), 5);
+    }
+}
@@ -0,0 +1,302 @@
+use atty::{self, Stream};
+use error::{GrappleError, Result};
+use rpassword;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use url::Url;
+
+/// A username and password found outside of the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Fills in credentials that weren't given explicitly or in the URL from the netrc file.
+///
+/// A username given without a password narrows the lookup to that user.
+pub fn resolve(
+    url: &Url,
+    username: Option<String>,
+    password: Option<String>,
+    netrc_file: Option<&Path>,
+) -> Result<(Option<String>, Option<String>)> {
+    let username = username
+        .or_else(|| Some(url.username().to_string()).filter(|username| !username.is_empty()));
+    if password.is_some() || url.password().is_some() {
+        return Ok((username, password));
+    }
+
+    let mut found = None;
+    if let (Some(netrc_file), Some(host)) = (netrc_file, url.host_str()) {
+        found = from_netrc(netrc_file, host, username.as_ref().map(|u| u.as_str()))?;
+    }
+
+    Ok(match found {
+        Some(credentials) => (Some(credentials.username), Some(credentials.password)),
+        None => (username, password),
+    })
+}
+
+/// Credentials to retry with once the server has asked for a password that wasn't found up
+/// front: the credential helper's, if one is set, otherwise a prompt when `prompt_for_password`.
+///
+/// The helper only runs here, so it isn't asked for credentials the server never wanted.
+pub fn on_challenge(
+    url: &Url,
+    credential_helper: Option<&str>,
+    prompt_for_password: bool,
+) -> Result<Option<Credentials>> {
+    let username = Some(url.username()).filter(|username| !username.is_empty());
+    let found = match credential_helper {
+        Some(command) => from_helper(command, url, username)?,
+        None => None,
+    };
+
+    match found {
+        Some(credentials) => Ok(Some(credentials)),
+        None if prompt_for_password => prompt(url),
+        None => Ok(None),
+    }
+}
+
+/// `~/.netrc`, or `%USERPROFILE%\_netrc` on Windows, if it exists.
+pub fn default_netrc_file() -> Option<PathBuf> {
+    let (home, name) = if cfg!(windows) {
+        (env::var_os("USERPROFILE"), "_netrc")
+    } else {
+        (env::var_os("HOME"), ".netrc")
+    };
+    home.map(|home| PathBuf::from(home).join(name))
+        .filter(|path| path.is_file())
+}
+
+/// Looks up `host` in a netrc file, falling back to its `default` entry.
+pub fn from_netrc(path: &Path, host: &str, username: Option<&str>) -> Result<Option<Credentials>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(parse_netrc(&contents, host, username))
+}
+
+/// Asks a git-credential style helper for credentials, by running `<command> get` with the
+/// request described on stdin.
+pub fn from_helper(
+    command: &str,
+    url: &Url,
+    username: Option<&str>,
+) -> Result<Option<Credentials>> {
+    let mut request = format!("protocol={}\n", url.scheme());
+    if let Some(host) = url.host_str() {
+        match url.port() {
+            Some(port) => request.push_str(&format!("host={}:{}\n", host, port)),
+            None => request.push_str(&format!("host={}\n", host)),
+        }
+    }
+    request.push_str(&format!("path={}\n", url.path().trim_start_matches('/')));
+    if let Some(username) = username {
+        request.push_str(&format!("username={}\n", username));
+    }
+    request.push('\n');
+
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(format!("{} get", command))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    if let Some(ref mut stdin) = child.stdin {
+        stdin.write_all(request.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(GrappleError::Auth(format!(
+            "Credential helper {} failed with {}.",
+            command, output.status
+        )));
+    }
+
+    Ok(parse_helper_output(
+        &String::from_utf8_lossy(&output.stdout),
+        username,
+    ))
+}
+
+/// Asks for a password on the terminal without echoing it, and for a username too if there
+/// isn't one. Returns `None` when stdin isn't a terminal, so scripts never hang on a prompt.
+pub fn prompt(url: &Url) -> Result<Option<Credentials>> {
+    if !atty::is(Stream::Stdin) {
+        return Ok(None);
+    }
+
+    let host = url.host_str().unwrap_or("");
+    let mut username = url.username().to_string();
+    if username.is_empty() {
+        print!("Username for {}: ", host);
+        io::stdout().flush()?;
+        io::stdin().read_line(&mut username)?;
+        username = username.trim().to_string();
+    }
+
+    let password =
+        rpassword::read_password_from_tty(Some(&format!("Password for {}@{}: ", username, host)))?;
+    Ok(Some(Credentials { username, password }))
+}
+
+fn parse_netrc(contents: &str, host: &str, username: Option<&str>) -> Option<Credentials> {
+    // Macro definitions run until the next blank line and can contain anything
+    let mut tokens = vec![];
+    let mut in_macro = false;
+    for line in contents.lines() {
+        if in_macro {
+            in_macro = !line.trim().is_empty();
+            continue;
+        }
+        for word in line.split_whitespace() {
+            if word.starts_with('#') {
+                break;
+            }
+            if word == "macdef" {
+                in_macro = true;
+                break;
+            }
+            tokens.push(word);
+        }
+    }
+
+    let mut entries: Vec<(Option<&str>, Option<&str>, Option<&str>)> = vec![];
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => entries.push((Some(tokens.next().unwrap_or("")), None, None)),
+            "default" => entries.push((None, None, None)),
+            "login" | "password" | "account" => {
+                let value = tokens.next();
+                if let Some(entry) = entries.last_mut() {
+                    match token {
+                        "login" => entry.1 = value,
+                        "password" => entry.2 = value,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let matches_user =
+        |login: Option<&str>| username.map_or(true, |username| login == Some(username));
+    entries
+        .iter()
+        .find(|entry| entry.0 == Some(host) && matches_user(entry.1))
+        .or_else(|| {
+            entries
+                .iter()
+                .find(|entry| entry.0.is_none() && matches_user(entry.1))
+        })
+        .and_then(|&(_, login, password)| {
+            Some(Credentials {
+                username: login.or(username)?.to_string(),
+                password: password?.to_string(),
+            })
+        })
+}
+
+fn parse_helper_output(output: &str, username: Option<&str>) -> Option<Credentials> {
+    let mut found_username = username.map(|username| username.to_string());
+    let mut found_password = None;
+    for line in output.lines() {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("username"), Some(value)) => found_username = Some(value.to_string()),
+            (Some("password"), Some(value)) => found_password = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Some(Credentials {
+        username: found_username?,
+        password: found_password?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const NETRC: &str = "# comment\n\
+                         machine files.example.com login alice password secret1\n\
+                         machine files.example.com\n  login bob\n  password secret2\n\
+                         macdef init\nmachine evil.com login mallory password nope\n\n\
+                         default login anonymous password guest\n";
+
+    fn credentials(username: &str, password: &str) -> Option<Credentials> {
+        Some(Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    #[test]
+    fn netrc_matches_machine_and_login() {
+        assert_eq!(
+            parse_netrc(NETRC, "files.example.com", None),
+            credentials("alice", "secret1")
+        );
+        assert_eq!(
+            parse_netrc(NETRC, "files.example.com", Some("bob")),
+            credentials("bob", "secret2")
+        );
+    }
+
+    #[test]
+    fn netrc_falls_back_to_default_and_skips_macros() {
+        assert_eq!(
+            parse_netrc(NETRC, "evil.com", None),
+            credentials("anonymous", "guest")
+        );
+        assert_eq!(parse_netrc(NETRC, "evil.com", Some("mallory")), None);
+    }
+
+    #[test]
+    fn helper_output() {
+        assert_eq!(
+            parse_helper_output("protocol=https\nusername=carol\npassword=p=w\n", None),
+            credentials("carol", "p=w")
+        );
+        assert_eq!(
+            parse_helper_output("password=pw\n", Some("dave")),
+            credentials("dave", "pw")
+        );
+        assert_eq!(parse_helper_output("username=erin\n", None), None);
+    }
+
+    #[test]
+    fn resolve_keeps_explicit_password() {
+        let url = Url::parse("http://files.example.com/file").unwrap();
+        assert_eq!(
+            resolve(&url, Some("u".to_string()), Some("p".to_string()), None).unwrap(),
+            (Some("u".to_string()), Some("p".to_string()))
+        );
+        let url = Url::parse("http://frank:pw@files.example.com/file").unwrap();
+        assert_eq!(
+            resolve(&url, None, None, Some(Path::new("/nonexistent/netrc"))).unwrap(),
+            (Some("frank".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn on_challenge_asks_helper() {
+        let url = Url::parse("http://files.example.com/file").unwrap();
+        assert!(on_challenge(&url, Some("false"), false).is_err());
+        assert_eq!(on_challenge(&url, None, false).unwrap(), None);
+    }
+}
@@ -0,0 +1,969 @@
+use checksum_helper::Checksum;
+use concurrency::Concurrency;
+use credential_helper;
+use error::{GrappleError, Result};
+use file_helper::{self, ConflictPolicy, Layout, ResumeStorage, TransferLimits};
+use progress::Progress;
+use rate_limit::RateLimiter;
+use request_helper::{self, HttpClient, Validators};
+use reqwest::header::{AcceptRanges, ContentLength, RangeUnit};
+use reqwest::{Client, StatusCode, Url};
+use scheduler::{Assignment, Scheduler};
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const DEFAULT_THREAD_COUNT: usize = 10;
+const DEFAULT_RETRIES: u32 = 5;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const ADAPTIVE_INTERVAL_SECS: u64 = 2;
+const RETRY_BASE_DELAY_MS: u64 = 500;
+const RETRY_MAX_DELAY_MS: u64 = 30_000;
+
+/// Configures and starts a segmented download.
+///
+/// Every option has a default, so `Downloader::new(url).start()` downloads the resource
+/// over 10 connections into the current working directory.
+#[derive(Debug)]
+pub struct Downloader {
+    url: Url,
+    thread_count: usize,
+    adaptive: bool,
+    part_count: Option<usize>,
+    username: Option<String>,
+    password: Option<String>,
+    netrc_file: Option<PathBuf>,
+    credential_helper: Option<String>,
+    prompt_for_password: bool,
+    thread_bandwidth: Option<u32>,
+    rate_limit: Option<u64>,
+    retries: u32,
+    timeout: Duration,
+    low_speed: Option<(u64, Duration)>,
+    output: Option<PathBuf>,
+    dir: Option<PathBuf>,
+    resume_storage: ResumeStorage,
+    on_conflict: ConflictPolicy,
+    etag_md5: bool,
+    progress_bars: bool,
+    checksum: Option<Checksum>,
+    client: Option<Client>,
+    headers: Vec<(String, String)>,
+}
+
+/// A download running in the background, returned by `Downloader::start`.
+#[derive(Debug)]
+pub struct DownloadHandle {
+    progress: Progress,
+    rate: Arc<RateLimiter>,
+    thread: JoinHandle<Result<Download>>,
+}
+
+/// A completed download.
+#[derive(Debug)]
+pub struct Download {
+    path: PathBuf,
+    len: u64,
+    skipped: bool,
+}
+
+impl Downloader {
+    pub fn new(url: Url) -> Downloader {
+        Downloader {
+            url,
+            thread_count: DEFAULT_THREAD_COUNT,
+            adaptive: false,
+            part_count: None,
+            username: None,
+            password: None,
+            netrc_file: None,
+            credential_helper: None,
+            prompt_for_password: false,
+            thread_bandwidth: None,
+            rate_limit: None,
+            retries: DEFAULT_RETRIES,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            low_speed: None,
+            output: None,
+            dir: None,
+            resume_storage: ResumeStorage::Footer,
+            on_conflict: ConflictPolicy::Fail,
+            etag_md5: false,
+            progress_bars: false,
+            checksum: None,
+            client: None,
+            headers: vec![],
+        }
+    }
+
+    /// Number of simultaneous connections, at least 1. The most connections used when
+    /// `adaptive` is set. A thread is only started for each connection actually opened.
+    pub fn threads(mut self, thread_count: usize) -> Downloader {
+        self.thread_count = thread_count;
+        self
+    }
+
+    /// Start with a couple of connections and add or drop them as throughput changes, backing
+    /// off when the server answers 429 or 503.
+    pub fn adaptive(mut self, adaptive: bool) -> Downloader {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Number of parts to split the download into, defaults to the thread count.
+    pub fn parts(mut self, part_count: usize) -> Downloader {
+        self.part_count = Some(part_count);
+        self
+    }
+
+    /// Username and password for Basic or Digest authentication, overriding any set in the URL.
+    pub fn credentials(mut self, username: Option<String>, password: Option<String>) -> Downloader {
+        self.username = username;
+        self.password = password;
+        self
+    }
+
+    /// Look up credentials missing from the URL and `credentials` in `~/.netrc`, if it exists.
+    pub fn netrc(mut self) -> Downloader {
+        self.netrc_file = credential_helper::default_netrc_file();
+        self
+    }
+
+    /// Look up credentials missing from the URL and `credentials` in this netrc file.
+    pub fn netrc_file<P: Into<PathBuf>>(mut self, path: P) -> Downloader {
+        self.netrc_file = Some(path.into());
+        self
+    }
+
+    /// Command to ask for credentials when the server wants a password that wasn't given or in
+    /// the netrc file, using the `git credential` helper protocol: it's run with `get` and sent
+    /// the request on stdin. Tried before prompting.
+    pub fn credential_helper<C: Into<String>>(mut self, command: C) -> Downloader {
+        self.credential_helper = Some(command.into());
+        self
+    }
+
+    /// Ask for a password on the terminal if the server wants one and none was found. Ignored
+    /// when stdin isn't a terminal.
+    pub fn prompt_for_password(mut self, prompt_for_password: bool) -> Downloader {
+        self.prompt_for_password = prompt_for_password;
+        self
+    }
+
+    /// Per connection bandwidth limit in kB/s.
+    pub fn thread_bandwidth(mut self, thread_bandwidth: u32) -> Downloader {
+        self.thread_bandwidth = Some(thread_bandwidth);
+        self
+    }
+
+    /// Limit on the combined speed of every connection in bytes per second, can be changed
+    /// while downloading with `DownloadHandle::set_rate_limit`.
+    pub fn limit_rate(mut self, bytes_per_second: u64) -> Downloader {
+        self.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Times to retry a part after a dropped connection or a 408, 429 or 5xx response, defaults
+    /// to 5. Each retry waits longer than the last, or as long as the server's `Retry-After` up
+    /// to 30 seconds. A download without range support starts over on each retry.
+    pub fn retries(mut self, retries: u32) -> Downloader {
+        self.retries = retries;
+        self
+    }
+
+    /// How long to wait for a connection, the response headers or the next data before a part
+    /// is restarted, defaults to 30 seconds. Not applied to a client supplied with `client`.
+    pub fn timeout(mut self, timeout: Duration) -> Downloader {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Restart a part whose connection averages fewer than `bytes_per_second` over `time`. Time
+    /// spent waiting on the bandwidth and rate limits isn't counted.
+    pub fn low_speed_limit(mut self, bytes_per_second: u64, time: Duration) -> Downloader {
+        self.low_speed = Some((bytes_per_second, time));
+        self
+    }
+
+    /// Where to save the file, defaults to the name given by the server in `Content-Disposition`
+    /// or the last segment of the URL. Relative paths are resolved against `dir`, if set.
+    pub fn output<P: Into<PathBuf>>(mut self, path: P) -> Downloader {
+        self.output = Some(path.into());
+        self
+    }
+
+    /// Directory to save the file in, defaults to the current working directory. Created if it
+    /// doesn't exist.
+    pub fn dir<P: Into<PathBuf>>(mut self, dir: P) -> Downloader {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Where to keep the state needed to resume an interrupted download, defaults to a footer
+    /// on the partial file.
+    pub fn resume_storage(mut self, resume_storage: ResumeStorage) -> Downloader {
+        self.resume_storage = resume_storage;
+        self
+    }
+
+    /// What to do if the target file already exists, defaults to failing.
+    pub fn on_conflict(mut self, on_conflict: ConflictPolicy) -> Downloader {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// Let `SkipIfIdentical` compare an existing file against an S3 style `ETag` as if it were
+    /// the MD5 of the file. Only used when no other checksum is known, never to verify downloads.
+    pub fn etag_md5(mut self, etag_md5: bool) -> Downloader {
+        self.etag_md5 = etag_md5;
+        self
+    }
+
+    /// Draw per part progress bars on stdout while downloading.
+    pub fn progress_bars(mut self, progress_bars: bool) -> Downloader {
+        self.progress_bars = progress_bars;
+        self
+    }
+
+    /// Digest the finished file must match, overriding any digest advertised by the server.
+    pub fn checksum(mut self, checksum: Checksum) -> Downloader {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// HTTP client shared by every request of the download, so connections are kept alive and
+    /// reused across parts. Supply one to configure proxies or TLS, defaults to a client with the
+    /// timeout.
+    ///
+    /// Idle connections stay pooled for 90 seconds, reqwest doesn't expose the pool size or idle
+    /// timeout so neither can be changed.
+    pub fn client(mut self, client: Client) -> Downloader {
+        self.client = Some(client);
+        self
+    }
+
+    /// Extra header sent with every request, e.g. an API key. An `Authorization` header replaces
+    /// Basic and Digest authentication. Adding a name again sends both values.
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Downloader {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// OAuth bearer token sent in the `Authorization` header of every request.
+    pub fn bearer_token<T: Into<String>>(self, token: T) -> Downloader {
+        let value = format!("Bearer {}", token.into());
+        self.header("Authorization", value)
+    }
+
+    /// Starts the download and blocks until it finishes.
+    pub fn download(self) -> Result<Download> {
+        self.start()?.wait()
+    }
+
+    /// Inspects the resource and starts downloading it on a background thread.
+    pub fn start(self) -> Result<DownloadHandle> {
+        let thread_count = self.thread_count;
+        let part_count = self.part_count.unwrap_or(thread_count);
+        let part_count_u64 = part_count as u64;
+
+        if thread_count < 1 {
+            return Err(GrappleError::InvalidOption(
+                "Thread count too low, must be at least 1.".to_string(),
+            ));
+        }
+
+        let mut url = self.url;
+        let (username, password) = credential_helper::resolve(
+            &url,
+            self.username,
+            self.password,
+            self.netrc_file.as_ref().map(|path| path.as_path()),
+        )?;
+        request_helper::override_username_password(&mut url, username, password)?;
+
+        let extra_headers = request_helper::extra_headers(self.headers);
+        let client = match self.client {
+            Some(client) => client,
+            None => Client::builder().timeout(self.timeout).build()?,
+        };
+        let client = HttpClient::new(client, extra_headers);
+        let res = match request_helper::head_request(&client, url.clone()) {
+            Err(GrappleError::PasswordRequired) => {
+                let credentials = credential_helper::on_challenge(
+                    &url,
+                    self.credential_helper
+                        .as_ref()
+                        .map(|command| command.as_str()),
+                    self.prompt_for_password,
+                )?
+                .ok_or(GrappleError::PasswordRequired)?;
+                request_helper::override_username_password(
+                    &mut url,
+                    Some(credentials.username),
+                    Some(credentials.password),
+                )?;
+                request_helper::head_request(&client, url.clone())?
+            }
+            res => res?,
+        };
+        let headers = res.headers();
+
+        let output = self.output.unwrap_or_else(|| {
+            PathBuf::from(request_helper::get_file_name(&url, res.url(), headers))
+        });
+        let mut path = match self.dir {
+            Some(dir) => dir.join(output),
+            None => output,
+        };
+
+        let content_length = headers
+            .get::<ContentLength>()
+            .map(|length_header| *length_header.deref());
+        let checksum = self.checksum.or_else(|| Checksum::from_headers(headers));
+
+        if path.exists() {
+            match self.on_conflict {
+                ConflictPolicy::Fail => return Err(GrappleError::AlreadyExists(path)),
+                ConflictPolicy::Overwrite => {}
+                ConflictPolicy::Rename => path = file_helper::unused_file_name(&path),
+                ConflictPolicy::Skip => return DownloadHandle::skipped(path),
+                ConflictPolicy::SkipIfIdentical => {
+                    let etag_checksum = if self.etag_md5 {
+                        Validators::from_headers(&url, headers)
+                            .etag
+                            .and_then(|etag| Checksum::from_etag(&etag))
+                    } else {
+                        None
+                    };
+                    if is_identical(
+                        &path,
+                        content_length,
+                        checksum.as_ref().or(etag_checksum.as_ref()),
+                    )? {
+                        return DownloadHandle::skipped(path);
+                    }
+                }
+            }
+        }
+
+        // Anything smaller than a chunk is fetched with a single request
+        let splittable =
+            content_length.map_or(false, |length| length >= file_helper::CHUNK_SIZE_U64);
+        let ranged = splittable
+            && match headers.get::<AcceptRanges>() {
+                Some(range_header) if range_header.deref().contains(&RangeUnit::Bytes) => true,
+                Some(range_header) if range_header.deref().contains(&RangeUnit::None) => false,
+                _ => request_helper::supports_range_requests(&client, url.clone())?,
+            };
+
+        // The partial file is kept next to the target, so its directory has to exist up front
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file_name = path.display().to_string();
+        let (plan, progress) = match content_length {
+            Some(content_length) if ranged => {
+                let validators = Validators::from_headers(&url, headers);
+                let (layout, restarted) = file_helper::create_file(
+                    &path,
+                    content_length,
+                    &validators,
+                    self.resume_storage,
+                )?;
+                if restarted && self.progress_bars {
+                    println!("Remote file changed since the partial download started, restarting.");
+                }
+
+                // Every part spans at least one whole chunk
+                let chunk_size = layout.chunk_size;
+                let part_count_u64 =
+                    cmp::max(1, cmp::min(part_count_u64, content_length / chunk_size));
+                let part_length = (content_length / part_count_u64) / chunk_size * chunk_size;
+
+                let mut sections: Vec<(u64, u64)> = vec![];
+                let mut lengths: Vec<u64> = vec![];
+                for section in 0..(part_count_u64 - 1) {
+                    sections.push((section * part_length, (section + 1) * part_length - 1));
+                    lengths.push(part_length);
+                }
+                sections.push(((part_count_u64 - 1) * part_length, content_length - 1));
+                lengths.push(content_length - (part_count_u64 - 1) * part_length);
+
+                let plan = Plan::Segmented {
+                    sections,
+                    layout,
+                    thread_count,
+                    if_range: validators.if_range().map(|if_range| if_range.to_string()),
+                };
+                (plan, Progress::new(&file_name, lengths, self.progress_bars))
+            }
+            Some(content_length) => (
+                Plan::Stream,
+                Progress::new(&file_name, vec![content_length], self.progress_bars),
+            ),
+            None => (
+                Plan::Stream,
+                Progress::unknown_length(&file_name, self.progress_bars),
+            ),
+        };
+
+        let rate = Arc::new(RateLimiter::new(self.rate_limit));
+        let job = Job {
+            client,
+            url,
+            path,
+            content_length,
+            plan,
+            checksum,
+            limits: TransferLimits {
+                bandwidth: self.thread_bandwidth,
+                rate: Arc::clone(&rate),
+                low_speed: self.low_speed,
+            },
+            retries: self.retries,
+            adaptive: self.adaptive,
+            progress: progress.clone(),
+        };
+
+        Ok(DownloadHandle {
+            progress,
+            rate,
+            thread: thread::spawn(move || job.run()),
+        })
+    }
+}
+
+impl DownloadHandle {
+    /// A handle for an existing file that was left alone rather than downloaded.
+    fn skipped(path: PathBuf) -> Result<DownloadHandle> {
+        let len = fs::metadata(&path)?.len();
+        let progress = Progress::new(&path.display().to_string(), vec![len], false);
+        progress.update(0, len);
+
+        Ok(DownloadHandle {
+            progress,
+            rate: Arc::new(RateLimiter::new(None)),
+            thread: thread::spawn(move || {
+                Ok(Download {
+                    path,
+                    len,
+                    skipped: true,
+                })
+            }),
+        })
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Current limit on the combined speed of every connection, in bytes per second.
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.rate.rate()
+    }
+
+    /// Changes the limit on the combined speed of every connection, `None` lifts it.
+    pub fn set_rate_limit(&self, bytes_per_second: Option<u64>) {
+        self.rate.set_rate(bytes_per_second);
+    }
+
+    /// Blocks until every part has finished and the file has been saved.
+    pub fn wait(self) -> Result<Download> {
+        self.thread.join().unwrap_or(Err(GrappleError::PartsFailed))
+    }
+}
+
+impl Download {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether an existing file was kept instead of downloading, see `ConflictPolicy`.
+    pub fn skipped(&self) -> bool {
+        self.skipped
+    }
+}
+
+/// How the bytes of a resource are fetched.
+enum Plan {
+    /// Parallel range requests into a partial file with a chunk footer, resumable.
+    Segmented {
+        sections: Vec<(u64, u64)>,
+        layout: Layout,
+        thread_count: usize,
+        if_range: Option<String>,
+    },
+    /// One plain GET over a single connection, for servers that don't honour ranges, resources
+    /// smaller than a chunk, or resources whose length isn't known up front.
+    Stream,
+}
+
+struct Job {
+    client: HttpClient,
+    url: Url,
+    path: PathBuf,
+    content_length: Option<u64>,
+    plan: Plan,
+    checksum: Option<Checksum>,
+    limits: TransferLimits,
+    retries: u32,
+    adaptive: bool,
+    progress: Progress,
+}
+
+impl Job {
+    fn run(self) -> Result<Download> {
+        let len = match self.plan {
+            Plan::Segmented {
+                ref sections,
+                layout,
+                thread_count,
+                ref if_range,
+            } => self.run_segmented(
+                sections,
+                layout,
+                thread_count,
+                if_range.as_ref().map(|if_range| if_range.as_str()),
+            )?,
+            Plan::Stream => self.run_stream()?,
+        };
+
+        Ok(Download {
+            path: self.path,
+            len,
+            skipped: false,
+        })
+    }
+
+    fn run_segmented(
+        &self,
+        sections: &[(u64, u64)],
+        layout: Layout,
+        thread_count: usize,
+        if_range: Option<&str>,
+    ) -> Result<u64> {
+        let scheduler = Arc::new(Scheduler::new(layout.chunk_size, sections.len()));
+        let mut missing_chunks = 0;
+        for (part, &(from, to)) in sections.iter().enumerate() {
+            let start = file_helper::get_first_empty_chunk(&self.path, layout, (from, to))?;
+            if start > to {
+                self.progress.update(part, to - from + 1);
+                self.progress.succeed(part);
+            } else {
+                self.progress.update(part, start - from);
+                scheduler.push(part, start, to);
+                missing_chunks += (to - start) / layout.chunk_size + 1;
+            }
+        }
+
+        let mut concurrency = if self.adaptive {
+            Concurrency::adaptive(thread_count)
+        } else {
+            Concurrency::fixed(thread_count)
+        };
+        let (events_tx, events) = mpsc::channel();
+        let worker = Worker {
+            client: self.client.clone(),
+            url: self.url.clone(),
+            path: self.path.clone(),
+            layout,
+            progress: self.progress.clone(),
+            scheduler: Arc::clone(&scheduler),
+            events: events_tx,
+            if_range: if_range.map(|if_range| if_range.to_string()),
+            limits: self.limits.clone(),
+            retries: self.retries,
+        };
+
+        // Workers are only started as ranges need them, and never more than there are chunks
+        let max_workers = cmp::min(thread_count as u64, missing_chunks) as usize;
+        let mut assignments: Vec<Sender<Assignment>> = vec![];
+        let mut workers = vec![];
+        let mut can_spawn = true;
+        let mut idle: Vec<usize> = vec![];
+        let mut active = 0;
+        let mut has_failed = false;
+        let mut first_error = None;
+        let interval = Duration::from_secs(ADAPTIVE_INTERVAL_SECS);
+        let mut retuned_at = Instant::now();
+        let mut downloaded = self.progress.downloaded();
+        let mut previous = 0;
+        loop {
+            while active < concurrency.target() {
+                if idle.is_empty() && (!can_spawn || workers.len() >= max_workers) {
+                    break;
+                }
+                let assignment = match scheduler.next() {
+                    Some(assignment) => assignment,
+                    None => break,
+                };
+                let worker_id = match idle.pop() {
+                    Some(worker_id) => worker_id,
+                    None => match spawn_worker(&worker, workers.len()) {
+                        Ok((assignments_tx, handle)) => {
+                            assignments.push(assignments_tx);
+                            workers.push(handle);
+                            workers.len() - 1
+                        }
+                        Err(e) => {
+                            // Carry on with the workers already running, if any
+                            can_spawn = false;
+                            scheduler.abandon(assignment.id);
+                            self.progress.fail(assignment.part);
+                            has_failed = true;
+                            first_error = first_error.or_else(|| Some(GrappleError::from(e)));
+                            continue;
+                        }
+                    },
+                };
+                self.progress.setting_up(assignment.part);
+                // Workers only stop once their channel is dropped, so this can't fail
+                let _ = assignments[worker_id].send(assignment);
+                active += 1;
+            }
+            if active == 0 {
+                break;
+            }
+
+            let event = if self.adaptive {
+                let timeout = interval
+                    .checked_sub(retuned_at.elapsed())
+                    .unwrap_or_else(|| Duration::new(0, 0));
+                events.recv_timeout(timeout).ok()
+            } else {
+                events.recv().ok()
+            };
+            match event {
+                Some(Event::Throttled) => concurrency.throttled(),
+                Some(Event::Done {
+                    worker_id,
+                    assignment,
+                    result,
+                }) => {
+                    active -= 1;
+                    idle.push(worker_id);
+                    match result {
+                        Ok(true) => {
+                            if scheduler.finish(assignment.id) {
+                                self.progress.succeed(assignment.part);
+                            }
+                        }
+                        result => {
+                            scheduler.abandon(assignment.id);
+                            self.progress.fail(assignment.part);
+                            has_failed = true;
+                            if let Err(e) = result {
+                                first_error = first_error.or(Some(e));
+                            }
+                        }
+                    }
+                }
+                None => {}
+            }
+
+            // Retune the number of connections from each interval's throughput
+            if self.adaptive && retuned_at.elapsed() >= interval {
+                let now = self.progress.downloaded();
+                let current = now.saturating_sub(downloaded);
+                concurrency.retune(previous, current);
+                previous = current;
+                downloaded = now;
+                retuned_at = Instant::now();
+            }
+        }
+
+        drop(assignments);
+        for worker in workers {
+            if worker.join().is_err() {
+                has_failed = true;
+            }
+        }
+
+        if has_failed {
+            Err(first_error.unwrap_or(GrappleError::PartsFailed))
+        } else {
+            let content_length = sections.last().map_or(0, |section| section.1 + 1);
+            self.finish(content_length, Some(layout))?;
+            Ok(content_length)
+        }
+    }
+
+    fn run_stream(&self) -> Result<u64> {
+        self.progress.setting_up(0);
+        match self.stream() {
+            Ok(Some(written)) => {
+                self.progress.succeed(0);
+                self.finish(written, None)?;
+                Ok(written)
+            }
+            Ok(None) => {
+                self.progress.fail(0);
+                Err(GrappleError::PartsFailed)
+            }
+            Err(e) => {
+                self.progress.fail(0);
+                Err(e)
+            }
+        }
+    }
+
+    /// Verifies the partial file against the checksum, if there is one, and moves it into place.
+    ///
+    /// On a mismatch the partial file is kept but its chunks are unmarked, so a rerun downloads
+    /// it again rather than resuming a complete file.
+    fn finish(&self, len: u64, layout: Option<Layout>) -> Result<()> {
+        if let Some(ref checksum) = self.checksum {
+            if let Err(e) = checksum.verify(&file_helper::tmp_file_name(&self.path), len) {
+                if let Some(layout) = layout {
+                    file_helper::clear_written_chunks(&self.path, layout, len)?;
+                }
+                return Err(e);
+            }
+        }
+        self.progress.complete();
+        file_helper::remove_footer_and_save(&self.path, len)
+    }
+
+    /// Streams the full body, returning the number of bytes saved if every byte arrived. Dropped
+    /// and stalled connections and transient server errors are retried from the first byte, as
+    /// there's no resume state to pick up from.
+    fn stream(&self) -> Result<Option<u64>> {
+        let mut attempt = 0;
+        loop {
+            let result = self.stream_body();
+            let retry_after = match result {
+                Ok(None) | Err(GrappleError::Network(_)) | Err(GrappleError::Timeout(_)) => None,
+                Err(GrappleError::HttpStatus(status, retry_after)) if is_transient(status) => {
+                    retry_after
+                }
+                _ => return result,
+            };
+            if attempt >= self.retries {
+                return result;
+            }
+
+            if let Err(GrappleError::Timeout(_)) = result {
+                self.progress.stalled(0);
+            } else {
+                self.progress.setting_up(0);
+            }
+            thread::sleep(retry_delay(retry_after, attempt));
+            attempt += 1;
+            self.progress.update(0, 0);
+        }
+    }
+
+    /// Makes a single attempt at streaming the full body.
+    fn stream_body(&self) -> Result<Option<u64>> {
+        let res = request_helper::get_request(&self.client, self.url.clone())?;
+        self.progress.start(0);
+        let written = file_helper::save_stream(&self.path, res, &self.progress, 0, &self.limits)?;
+        Ok(written.filter(|&written| {
+            self.content_length
+                .map_or(true, |content_length| content_length == written)
+        }))
+    }
+}
+
+/// Whether the file at `path` has the expected length and, if one is known, checksum.
+fn is_identical(
+    path: &Path,
+    content_length: Option<u64>,
+    checksum: Option<&Checksum>,
+) -> Result<bool> {
+    let len = fs::metadata(path)?.len();
+    if content_length != Some(len) {
+        return Ok(false);
+    }
+
+    match checksum {
+        Some(checksum) => checksum.matches(path, len),
+        None => Ok(true),
+    }
+}
+
+/// What a worker tells the pool.
+enum Event {
+    /// The server pushed back with a 429 or 503.
+    Throttled,
+    /// A range was downloaded or given up on, and the worker is free again.
+    Done {
+        worker_id: usize,
+        assignment: Assignment,
+        result: Result<bool>,
+    },
+}
+
+/// Starts a worker thread that downloads the ranges sent on the returned channel, reporting each
+/// one back on the worker's event channel.
+fn spawn_worker(
+    worker: &Worker,
+    worker_id: usize,
+) -> io::Result<(Sender<Assignment>, JoinHandle<()>)> {
+    let (assignments_tx, assignments_rx) = mpsc::channel();
+    let worker = worker.clone();
+    let handle = thread::Builder::new().spawn(move || {
+        for assignment in assignments_rx {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| worker.download(assignment)))
+                .unwrap_or_else(|_| Err(GrappleError::PartsFailed));
+            let event = Event::Done {
+                worker_id,
+                assignment,
+                result,
+            };
+            if worker.events.send(event).is_err() {
+                break;
+            }
+        }
+    })?;
+    Ok((assignments_tx, handle))
+}
+
+/// Everything a worker thread needs to download ranges handed out by the scheduler.
+#[derive(Clone)]
+struct Worker {
+    client: HttpClient,
+    url: Url,
+    path: PathBuf,
+    layout: Layout,
+    progress: Progress,
+    scheduler: Arc<Scheduler>,
+    events: Sender<Event>,
+    if_range: Option<String>,
+    limits: TransferLimits,
+    retries: u32,
+}
+
+impl Worker {
+    /// Downloads an assigned range, returning whether all of it arrived. Dropped and stalled
+    /// connections and transient server errors are retried from the first chunk not on disk.
+    fn download(&self, mut assignment: Assignment) -> Result<bool> {
+        let mut attempt = 0;
+        loop {
+            let result = self.download_range(&assignment);
+            let retry_after = match result {
+                Ok(false) | Err(GrappleError::Network(_)) | Err(GrappleError::Timeout(_)) => None,
+                Err(GrappleError::HttpStatus(status, retry_after)) if is_transient(status) => {
+                    if status == StatusCode::TooManyRequests
+                        || status == StatusCode::ServiceUnavailable
+                    {
+                        let _ = self.events.send(Event::Throttled);
+                    }
+                    retry_after
+                }
+                _ => return result,
+            };
+            if attempt >= self.retries {
+                return result;
+            }
+
+            if let Err(GrappleError::Timeout(_)) = result {
+                self.progress.stalled(assignment.part);
+            } else {
+                self.progress.setting_up(assignment.part);
+            }
+            thread::sleep(retry_delay(retry_after, attempt));
+            attempt += 1;
+
+            let end = self.scheduler.end(assignment.id);
+            let start = file_helper::get_first_empty_chunk(
+                &self.path,
+                self.layout,
+                (assignment.start, end),
+            )?;
+            let (rewound, resumed) = self.scheduler.resume(assignment.id, start);
+            self.progress.rewind(assignment.part, rewound);
+            assignment = match resumed {
+                Some(resumed) => resumed,
+                None => return Ok(true),
+            };
+        }
+    }
+
+    /// Makes a single attempt at an assigned range. The range may shrink while downloading if
+    /// an idle worker takes over its tail.
+    fn download_range(&self, assignment: &Assignment) -> Result<bool> {
+        let range_req = request_helper::get_range_request(
+            &self.client,
+            self.url.clone(),
+            (assignment.start, assignment.end),
+            self.if_range.as_ref().map(|if_range| if_range.as_str()),
+        )?;
+        self.progress.start(assignment.part);
+        file_helper::save_response(
+            &self.path,
+            range_req,
+            self.layout,
+            &self.progress,
+            &self.scheduler,
+            assignment,
+            &self.limits,
+        )
+    }
+}
+
+/// Whether a status is worth retrying after a pause.
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::RequestTimeout
+        || status == StatusCode::TooManyRequests
+        || status.is_server_error()
+}
+
+/// How long to wait before retrying: as long as the server asked, capped so a far off
+/// `Retry-After` can't stall the download, or exponential backoff if it didn't say.
+fn retry_delay(retry_after: Option<Duration>, attempt: u32) -> Duration {
+    match retry_after {
+        Some(retry_after) => cmp::min(retry_after, Duration::from_millis(RETRY_MAX_DELAY_MS)),
+        None => backoff(attempt),
+    }
+}
+
+/// Exponential backoff with jitter, so parts that failed together don't retry in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let delay = cmp::min(
+        RETRY_MAX_DELAY_MS,
+        RETRY_BASE_DELAY_MS << cmp::min(attempt, 16),
+    );
+    let jitter = RandomState::new().build_hasher().finish() % (delay / 2 + 1);
+    Duration::from_millis(delay - jitter)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn retry_delay_caps_retry_after() {
+        assert_eq!(
+            retry_delay(Some(Duration::from_secs(5)), 0),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            retry_delay(Some(Duration::from_secs(86_400)), 0),
+            Duration::from_millis(RETRY_MAX_DELAY_MS)
+        );
+        assert!(retry_delay(None, 0) <= Duration::from_millis(RETRY_BASE_DELAY_MS));
+        assert!(retry_delay(None, 20) <= Duration::from_millis(RETRY_MAX_DELAY_MS));
+    }
+}
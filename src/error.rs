@@ -0,0 +1,142 @@
+use reqwest::{self, StatusCode};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub type Result<T> = ::std::result::Result<T, GrappleError>;
+
+/// Everything that can stop a download.
+///
+/// Each variant maps to a distinct process exit code, see `GrappleError::exit_code`.
+#[derive(Debug)]
+pub enum GrappleError {
+    /// One or more parts did not finish, the partial file is kept for a rerun.
+    PartsFailed,
+    /// A download option was out of range or inconsistent with another option.
+    InvalidOption(String),
+    /// The target file is already present on disk.
+    AlreadyExists(PathBuf),
+    /// The connection to the server failed or was interrupted.
+    Network(reqwest::Error),
+    /// A connection went quiet or dropped below the minimum speed, and was given up on.
+    Timeout(String),
+    /// The server answered with a non 2xx status, along with any `Retry-After` delay it asked for.
+    HttpStatus(StatusCode, Option<Duration>),
+    /// Authentication could not be negotiated with the server.
+    Auth(String),
+    /// The server asked for credentials but no password was given.
+    PasswordRequired,
+    /// The server does not support something the download requires.
+    Unsupported(String),
+    /// Reading or writing the file on disk failed.
+    Io(io::Error),
+    /// The partial file left by a previous run cannot be trusted.
+    ResumeCorrupted(String),
+    /// The finished download didn't match the expected checksum, the partial file is kept.
+    ChecksumMismatch(String),
+}
+
+impl GrappleError {
+    /// Process exit code for this kind of failure.
+    ///
+    /// | Code | Meaning                                          |
+    /// |------|--------------------------------------------------|
+    /// | 1    | Some parts failed, rerun to resume               |
+    /// | 2    | Invalid option                                   |
+    /// | 3    | Target file already exists                       |
+    /// | 4    | Network error or timeout                         |
+    /// | 5    | Server responded with a non 2xx status           |
+    /// | 6    | Authentication failed                            |
+    /// | 7    | Server does not support a required capability    |
+    /// | 8    | Disk IO error                                    |
+    /// | 9    | Partial file from a previous run is corrupt      |
+    /// | 10   | Checksum verification failed                     |
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            GrappleError::PartsFailed => 1,
+            GrappleError::InvalidOption(_) => 2,
+            GrappleError::AlreadyExists(_) => 3,
+            GrappleError::Network(_) | GrappleError::Timeout(_) => 4,
+            GrappleError::HttpStatus(..) => 5,
+            GrappleError::Auth(_) | GrappleError::PasswordRequired => 6,
+            GrappleError::Unsupported(_) => 7,
+            GrappleError::Io(_) => 8,
+            GrappleError::ResumeCorrupted(_) => 9,
+            GrappleError::ChecksumMismatch(_) => 10,
+        }
+    }
+}
+
+impl fmt::Display for GrappleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GrappleError::PartsFailed => write!(f, "Some parts failed to download, please rerun."),
+            GrappleError::InvalidOption(ref message) => write!(f, "{}", message),
+            GrappleError::AlreadyExists(ref path) => write!(
+                f,
+                "{} already exists, please remove it and try again.",
+                path.display()
+            ),
+            GrappleError::Network(ref e) => write!(f, "Network error: {}", e),
+            GrappleError::Timeout(ref message) => write!(f, "Timed out: {}", message),
+            GrappleError::HttpStatus(ref status, _) => {
+                write!(f, "Didn't get a 2xx response. Status: {}", status)
+            }
+            GrappleError::Auth(ref message) => write!(f, "Authentication failed: {}", message),
+            GrappleError::PasswordRequired => write!(
+                f,
+                "Authentication failed: the server requires a password, pass one with --password, \
+                 GRAPPLE_PASSWORD, a netrc file or a credential helper."
+            ),
+            GrappleError::Unsupported(ref message) => write!(f, "{}", message),
+            GrappleError::Io(ref e) => write!(f, "IO error: {}", e),
+            GrappleError::ResumeCorrupted(ref message) => {
+                write!(f, "Cannot resume download: {}", message)
+            }
+            GrappleError::ChecksumMismatch(ref message) => {
+                write!(f, "Checksum verification failed: {}", message)
+            }
+        }
+    }
+}
+
+impl Error for GrappleError {
+    fn description(&self) -> &str {
+        match *self {
+            GrappleError::PartsFailed => "some parts failed to download",
+            GrappleError::InvalidOption(_) => "invalid option",
+            GrappleError::AlreadyExists(_) => "target already exists",
+            GrappleError::Network(_) => "network error",
+            GrappleError::Timeout(_) => "timed out",
+            GrappleError::HttpStatus(..) => "unsuccessful http status",
+            GrappleError::Auth(_) => "authentication failed",
+            GrappleError::PasswordRequired => "password required",
+            GrappleError::Unsupported(_) => "unsupported by server",
+            GrappleError::Io(_) => "io error",
+            GrappleError::ResumeCorrupted(_) => "partial file corrupt",
+            GrappleError::ChecksumMismatch(_) => "checksum mismatch",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            GrappleError::Network(ref e) => Some(e),
+            GrappleError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GrappleError {
+    fn from(e: io::Error) -> GrappleError {
+        GrappleError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for GrappleError {
+    fn from(e: reqwest::Error) -> GrappleError {
+        GrappleError::Network(e)
+    }
+}
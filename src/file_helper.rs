@@ -1,13 +1,19 @@
+use error::{GrappleError, Result};
+use progress::Progress;
+use rate_limit::RateLimiter;
+use request_helper::Validators;
 use reqwest::header::{ContentRange, ContentRangeSpec};
-use reqwest::{Error, Response};
+use reqwest::{Response, StatusCode};
+use resume_helper::{self, ResumeState};
+use scheduler::{Assignment, Scheduler};
+use std::ffi::OsString;
 use std::fs::{self, rename, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::mem::transmute;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::ops::Deref;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
-use ui_helper;
 
 lazy_static! {
     static ref FLOCK: Mutex<()> = Mutex::new(());
@@ -16,129 +22,335 @@ lazy_static! {
 pub const CHUNK_SIZE_USIZE: usize = 128 * 1024;
 pub const CHUNK_SIZE_U64: u64 = 128 * 1024;
 
-pub fn create_file(path: &str, bytes: u64) -> u64 {
-    let (chunk_count, chunk_space) = calculate_chunk_count_and_space(bytes);
-    let tmp_name = tmp_file_name(path);
+/// Where the resume state of a partial download is kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResumeStorage {
+    /// Appended to the end of the `.grapplepartial` data file, removed once complete.
+    Footer,
+    /// In a separate `.grapple` control file, so the data file is exactly the target size.
+    Sidecar,
+}
 
-    let footer_space = chunk_space + 8;
-    let footer_space_u64 = footer_space as u64;
-    let existing_file_length = fs::metadata(tmp_name.clone())
-        .map(|metadata| metadata.len())
-        .unwrap_or(0);
+/// What to do when the target file already exists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Stop with `GrappleError::AlreadyExists`.
+    Fail,
+    /// Download anyway and replace the existing file.
+    Overwrite,
+    /// Save to the first free `name (n).ext` alongside it.
+    Rename,
+    /// Leave the existing file and don't download.
+    Skip,
+    /// Skip if the existing file has the expected size and, where known, checksum or MD5 ETag,
+    /// otherwise overwrite it.
+    SkipIfIdentical,
+}
 
-    if existing_file_length != footer_space_u64 + bytes {
-        let mut buf: Vec<u8> = vec![0_u8; chunk_space];
+/// Where the chunk bitmap of a partial file lives and how much data each bit covers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Layout {
+    pub chunk_size: u64,
+    /// Distance from the end of the state file to the start of the bitmap.
+    pub bitmap_offset: u64,
+    /// Distance from the end of the state file to the start of the chunk hashes, if it has any.
+    pub hash_offset: Option<u64>,
+    pub storage: ResumeStorage,
+}
 
-        #[allow(unsafe_code)]
-        let chunk_count_bytes: [u8; 8] = unsafe { transmute(chunk_count.to_be()) };
-        buf.extend_from_slice(&chunk_count_bytes);
+/// Limits applied to each connection while a response is saved.
+#[derive(Clone, Debug)]
+pub struct TransferLimits {
+    /// Bandwidth cap in kB/s.
+    pub bandwidth: Option<u32>,
+    /// Cap on the combined speed of every connection.
+    pub rate: Arc<RateLimiter>,
+    /// Give up on a connection that averages fewer bytes per second than this over the duration.
+    pub low_speed: Option<(u64, Duration)>,
+}
 
-        let mut file = File::create(tmp_name).unwrap();
-        file.set_len(footer_space_u64 + bytes).unwrap();
-        file.seek(SeekFrom::End(-(footer_space_u64 as i64)))
-            .unwrap();
-        file.write_all(&buf[..]).unwrap();
+impl Layout {
+    fn from_state(state: &ResumeState, storage: ResumeStorage) -> Layout {
+        Layout {
+            chunk_size: state.chunk_size,
+            bitmap_offset: state.bitmap_offset(),
+            hash_offset: state.hash_offset(),
+            storage,
+        }
     }
-    footer_space_u64
 }
 
-pub fn remove_footer_and_save(path: &str, bytes: u64) {
+/// Creates the partial file and its resume state, or reuses ones left by a previous run.
+///
+/// A partial file whose validators no longer match the remote file is replaced, in which case
+/// the returned flag is set. Partial files from before the footer was versioned are resumed
+/// without validation.
+pub fn create_file(
+    path: &Path,
+    bytes: u64,
+    validators: &Validators,
+    storage: ResumeStorage,
+) -> Result<(Layout, bool)> {
+    let tmp_name = tmp_file_name(path);
+    let existing = match storage {
+        ResumeStorage::Footer => read_footer(&tmp_name, bytes)?,
+        ResumeStorage::Sidecar => read_sidecar(&tmp_name, &control_file_name(path), bytes)?,
+    };
+
+    let restarted = match existing {
+        Some((layout, None)) => return Ok((layout, false)),
+        Some((layout, Some(stored))) => {
+            if stored.url != validators.url {
+                return Err(GrappleError::ResumeCorrupted(format!(
+                    "{} was started from {}, remove it to download from {}",
+                    tmp_name.display(),
+                    stored.url,
+                    validators.url
+                )));
+            }
+            if !stored.changed(validators) {
+                return Ok((layout, false));
+            }
+            true
+        }
+        None => false,
+    };
+
+    let state = ResumeState {
+        version: resume_helper::VERSION,
+        chunk_size: CHUNK_SIZE_U64,
+        total_length: bytes,
+        validators: validators.clone(),
+    };
+    let state_block = resume_helper::encode(&state);
+
+    match storage {
+        ResumeStorage::Footer => {
+            let footer_space = state_block.len() as u64;
+            let mut file = File::create(tmp_name)?;
+            file.set_len(footer_space + bytes)?;
+            file.seek(SeekFrom::End(-(footer_space as i64)))?;
+            file.write_all(&state_block)?;
+        }
+        ResumeStorage::Sidecar => {
+            let file = File::create(tmp_name)?;
+            file.set_len(bytes)?;
+            File::create(control_file_name(path))?.write_all(&state_block)?;
+        }
+    }
+
+    Ok((Layout::from_state(&state, storage), restarted))
+}
+
+/// The first `name (n).ext` next to `path` that doesn't exist yet.
+pub fn unused_file_name(path: &Path) -> PathBuf {
+    (1..)
+        .map(|n| numbered_file_name(path, n))
+        .find(|candidate| !candidate.exists())
+        .expect("Ran out of file names")
+}
+
+/// Strips any resume state footer, moves the partial file into place and removes its control
+/// file, if there is one.
+pub fn remove_footer_and_save(path: &Path, bytes: u64) -> Result<()> {
     let tmp_path = tmp_file_name(path);
-    let file = OpenOptions::new()
+    let file = OpenOptions::new().write(true).open(&tmp_path)?;
+    file.set_len(bytes)?;
+    rename(tmp_path, path)?;
+
+    if let Err(e) = fs::remove_file(control_file_name(path)) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Unmarks every chunk of a partial file of `bytes`, keeping its data, so the next run downloads
+/// all of it again.
+pub fn clear_written_chunks(path: &Path, layout: Layout, bytes: u64) -> Result<()> {
+    let (_, chunk_space) = resume_helper::calculate_chunk_count_and_space(bytes, layout.chunk_size);
+
+    let _guard = FLOCK
+        .lock()
+        .expect("Failed to acquire lock, lock poisoned!");
+    let mut file = OpenOptions::new()
         .write(true)
-        .open(tmp_path.clone())
-        .unwrap();
-    file.set_len(bytes).unwrap();
-    rename(tmp_path, path).unwrap();
+        .open(state_file_name(path, layout))?;
+    file.seek(SeekFrom::End(-(layout.bitmap_offset as i64)))?;
+    file.write_all(&vec![0; chunk_space])?;
+    Ok(())
 }
 
+/// Writes a range response into the partial file until its assignment runs out, returning
+/// whether every byte of it arrived.
 pub fn save_response(
-    path: &str,
+    path: &Path,
     mut res: Response,
-    footer_space: u64,
-    child_id: usize,
-    prefilled: u64,
-    thread_bandwidth: Option<u32>,
-) -> Result<u64, Error> {
-    let first_byte = match *res.headers().get::<ContentRange>().unwrap().deref() {
-        ContentRangeSpec::Bytes {
-            range,
+    layout: Layout,
+    progress: &Progress,
+    scheduler: &Scheduler,
+    assignment: &Assignment,
+    limits: &TransferLimits,
+) -> Result<bool> {
+    if res.status() == StatusCode::Ok {
+        return Err(GrappleError::ResumeCorrupted(
+            "the server sent the whole file instead of the requested range, the remote file \
+             may have changed, rerun to start over"
+                .to_string(),
+        ));
+    }
+
+    let first_byte = match res
+        .headers()
+        .get::<ContentRange>()
+        .map(|header| header.deref())
+    {
+        Some(&ContentRangeSpec::Bytes {
+            range: Some((first_byte, _)),
             instance_length: _,
-        } => range.unwrap().0,
-        _ => panic!("Response header of incorrect form!"),
+        }) if first_byte == assignment.start => first_byte,
+        _ => {
+            return Err(GrappleError::Unsupported(
+                "Response header of incorrect form!".to_string(),
+            ))
+        }
     };
 
     let file_name = tmp_file_name(path);
-    let mut file = OpenOptions::new()
-        .write(true)
-        .read(true)
-        .open(file_name)
-        .unwrap();
+    let mut file = OpenOptions::new().write(true).read(true).open(file_name)?;
 
-    file.seek(SeekFrom::Start(first_byte)).unwrap();
+    file.seek(SeekFrom::Start(first_byte))?;
     let mut buf = [0; CHUNK_SIZE_USIZE];
     let mut written = 0;
-    let mut last_bw_sync = Instant::now();
-    let mut bytes_since_bw_sync: f64 = 0.0;
-    let bandwidth = thread_bandwidth.map(|bw| f64::from(bw) * 1024_f64);
-    let bytes_between_bw_sync = bandwidth.unwrap_or(0.0) * 0.1;
+    let mut throttle = Throttle::new(limits.bandwidth);
+    let mut speed_check = SpeedCheck::new(limits.low_speed);
+    let mut hasher = ChunkHasher::new(layout.chunk_size, first_byte);
+
+    loop {
+        let len = match read_or_timeout(&mut res, &mut buf)? {
+            Some(len) => len,
+            None => return Ok(false),
+        };
+        if len == 0 {
+            return Ok(scheduler.is_complete(assignment.id));
+        }
 
-    while let Ok(len) = res.read(&mut buf) {
+        // The range may have been split since the request went out, dropping the response
+        // aborts it at the split point
+        let len = scheduler.claim(assignment.id, len as u64) as usize;
         if len == 0 {
-            return Ok(written + prefilled);
+            return Ok(true);
         }
-        file.write_all(&buf[..len]).unwrap();
-        let last_working_chunk = (written + first_byte) / CHUNK_SIZE_U64;
+        file.write_all(&buf[..len])?;
+        let first_hashed_chunk = hasher.chunk;
+        let hashes = hasher.update(&buf[..len]);
+        set_chunk_hashes(path, layout, first_hashed_chunk, &hashes)?;
+        let last_working_chunk = (written + first_byte) / layout.chunk_size;
         written += len as u64;
-        let current_working_chunk = (written + first_byte) / CHUNK_SIZE_U64;
-        set_written_chunks(
-            path,
-            footer_space,
-            (last_working_chunk, current_working_chunk),
-        );
-        ui_helper::update_bar(child_id, written + prefilled);
+        let current_working_chunk = (written + first_byte) / layout.chunk_size;
+        set_written_chunks(path, layout, (last_working_chunk, current_working_chunk))?;
+        progress.advance(assignment.part, len as u64);
+        if scheduler.is_complete(assignment.id) {
+            return Ok(true);
+        }
+        speed_check.update(len)?;
+        speed_check.pause(throttle.wait(len) + limits.rate.take(len));
+    }
+}
 
-        if let Some(bw) = bandwidth {
-            bytes_since_bw_sync += len as f64;
+/// Writes a whole response body into a fresh partial file, for servers that can't serve ranges
+/// or resources of unknown length.
+///
+/// There is no chunk footer as the download can't be resumed, so the partial file grows as data
+/// arrives and only ever holds the bytes received so far. Returns the number of bytes written,
+/// or `None` if the connection dropped before the body ended.
+pub fn save_stream(
+    path: &Path,
+    mut res: Response,
+    progress: &Progress,
+    child_id: usize,
+    limits: &TransferLimits,
+) -> Result<Option<u64>> {
+    let mut file = File::create(tmp_file_name(path))?;
+    let mut buf = [0; CHUNK_SIZE_USIZE];
+    let mut written = 0;
+    let mut throttle = Throttle::new(limits.bandwidth);
+    let mut speed_check = SpeedCheck::new(limits.low_speed);
 
-            if bytes_since_bw_sync >= bytes_between_bw_sync {
-                let seconds_wait = len as f64 / bw;
-                let wait_time = Duration::new(
-                    seconds_wait.trunc() as u64,
-                    (seconds_wait * 1_000_000_000_f64) as u32,
-                );
-                let time_passed = Instant::now() - last_bw_sync;
+    loop {
+        let len = match read_or_timeout(&mut res, &mut buf)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if len == 0 {
+            return Ok(Some(written));
+        }
+        file.write_all(&buf[..len])?;
+        written += len as u64;
+        progress.update(child_id, written);
+        speed_check.update(len)?;
+        speed_check.pause(throttle.wait(len) + limits.rate.take(len));
+    }
+}
 
-                if wait_time.gt(&time_passed) {
-                    thread::sleep(wait_time - time_passed);
-                }
+/// Reads the next block of a response, returning `None` if the connection broke and an error
+/// if it went quiet for longer than the client's timeout.
+fn read_or_timeout(res: &mut Response, buf: &mut [u8]) -> Result<Option<usize>> {
+    match res.read(buf) {
+        Ok(len) => Ok(Some(len)),
+        Err(ref e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => Err(
+            GrappleError::Timeout("no data received within the timeout".to_string()),
+        ),
+        Err(_) => Ok(None),
+    }
+}
 
-                last_bw_sync = Instant::now();
+/// Finds the first byte in `byte_range` that still needs downloading.
+///
+/// Chunks marked as written are checked against their stored hashes, so a chunk that didn't
+/// make it to disk intact is fetched again along with everything after it in the range.
+pub fn get_first_empty_chunk(path: &Path, layout: Layout, byte_range: (u64, u64)) -> Result<u64> {
+    let first_chunk = byte_range.0 / layout.chunk_size;
+    let (empty_chunk, hashes) = {
+        let _guard = FLOCK
+            .lock()
+            .expect("Failed to acquire lock, lock poisoned!");
+        let empty_chunk = get_first_unmarked_chunk(path, layout, byte_range)?;
+        let hashes = get_chunk_hashes(path, layout, first_chunk, empty_chunk)?;
+        (empty_chunk, hashes)
+    };
+
+    if let Some(hashes) = hashes {
+        let mut file = File::open(tmp_file_name(path))?;
+        file.seek(SeekFrom::Start(first_chunk * layout.chunk_size))?;
+        let mut buf = vec![0; layout.chunk_size as usize];
+        for (chunk_num, &hash) in (first_chunk..empty_chunk).zip(hashes.iter()) {
+            file.read_exact(&mut buf)?;
+            if resume_helper::chunk_hash(&buf) != hash {
+                return Ok(chunk_num * layout.chunk_size);
             }
         }
     }
 
-    Ok(0u64)
+    Ok(empty_chunk * layout.chunk_size)
 }
 
-pub fn get_first_empty_chunk(path: &str, footer_space: u64, byte_range: (u64, u64)) -> u64 {
-    let _guard = FLOCK
-        .lock()
-        .expect("Failed to acquire lock, lock poisoned!");
+/// Chunk number of the first chunk in `byte_range` whose bit isn't set. Must hold `FLOCK`.
+fn get_first_unmarked_chunk(path: &Path, layout: Layout, byte_range: (u64, u64)) -> Result<u64> {
     let mut file = OpenOptions::new()
         .read(true)
-        .open(tmp_file_name(path))
-        .unwrap();
-    let first_chunk = byte_range.0 / CHUNK_SIZE_U64;
-    let last_chunk = byte_range.1 / CHUNK_SIZE_U64;
-    let first_byte = get_chunk_status_offset(footer_space as i64, first_chunk as i64);
-    let last_byte = get_chunk_status_offset(footer_space as i64, last_chunk as i64);
+        .open(state_file_name(path, layout))?;
+    let first_chunk = byte_range.0 / layout.chunk_size;
+    let last_chunk = byte_range.1 / layout.chunk_size;
+    let first_byte = get_chunk_status_offset(layout.bitmap_offset as i64, first_chunk as i64);
+    let last_byte = get_chunk_status_offset(layout.bitmap_offset as i64, last_chunk as i64);
 
-    file.seek(SeekFrom::End(first_byte)).unwrap();
+    file.seek(SeekFrom::End(first_byte))?;
     let mut buf = [0; 1];
     let mut chunk_num = first_chunk;
     for byte_num in first_byte..=last_byte {
-        file.read_exact(&mut buf).unwrap();
+        file.read_exact(&mut buf)?;
 
         let start_offset = if byte_num == first_byte {
             first_chunk % 8
@@ -156,18 +368,67 @@ pub fn get_first_empty_chunk(path: &str, footer_space: u64, byte_range: (u64, u6
 
         for bit_offset in start_offset..finish_offset {
             if byte & (1 << (7 - bit_offset)) == 0 {
-                return chunk_num * CHUNK_SIZE_U64;
+                return Ok(chunk_num);
             }
             chunk_num += 1;
         }
     }
-    chunk_num * CHUNK_SIZE_U64
+    Ok(chunk_num)
 }
 
-fn set_written_chunks(path: &str, footer_space: u64, working_chunk_from_to: (u64, u64)) {
+/// Stored hashes of chunks `from..to`, or `None` if the layout doesn't record hashes. Must hold
+/// `FLOCK`.
+fn get_chunk_hashes(path: &Path, layout: Layout, from: u64, to: u64) -> Result<Option<Vec<u32>>> {
+    let hash_offset = match layout.hash_offset {
+        Some(hash_offset) => hash_offset as i64,
+        None => return Ok(None),
+    };
+    if to <= from {
+        return Ok(Some(vec![]));
+    }
+
+    let mut file = File::open(state_file_name(path, layout))?;
+    let mut buf = vec![0; ((to - from) * 4) as usize];
+    file.seek(SeekFrom::End(-hash_offset + from as i64 * 4))?;
+    file.read_exact(&mut buf)?;
+    Ok(Some(buf.chunks(4).map(resume_helper::be_u32).collect()))
+}
+
+/// Records the hashes of consecutive chunks starting at `first_chunk`, ahead of their bits being
+/// set.
+fn set_chunk_hashes(path: &Path, layout: Layout, first_chunk: u64, hashes: &[u32]) -> Result<()> {
+    let hash_offset = match layout.hash_offset {
+        Some(hash_offset) => hash_offset as i64,
+        None => return Ok(()),
+    };
+    if hashes.is_empty() {
+        return Ok(());
+    }
+
+    let mut buf = Vec::with_capacity(hashes.len() * 4);
+    for &hash in hashes {
+        buf.extend_from_slice(&resume_helper::u32_to_be_bytes(hash));
+    }
+
+    let _guard = FLOCK
+        .lock()
+        .expect("Failed to acquire lock, lock poisoned!");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(state_file_name(path, layout))?;
+    file.seek(SeekFrom::End(-hash_offset + first_chunk as i64 * 4))?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+fn set_written_chunks(
+    path: &Path,
+    layout: Layout,
+    working_chunk_from_to: (u64, u64),
+) -> Result<()> {
     let (last_working_chunk, current_working_chunk) = working_chunk_from_to;
     if current_working_chunk <= last_working_chunk {
-        return;
+        return Ok(());
     }
 
     let current_complete_chunk = current_working_chunk - 1;
@@ -178,15 +439,16 @@ fn set_written_chunks(path: &str, footer_space: u64, working_chunk_from_to: (u64
     let mut file = OpenOptions::new()
         .write(true)
         .read(true)
-        .open(tmp_file_name(path))
-        .unwrap();
+        .open(state_file_name(path, layout))?;
 
-    let first_byte = get_chunk_status_offset(footer_space as i64, last_working_chunk as i64);
-    let last_byte = get_chunk_status_offset(footer_space as i64, current_complete_chunk as i64);
+    let first_byte =
+        get_chunk_status_offset(layout.bitmap_offset as i64, last_working_chunk as i64);
+    let last_byte =
+        get_chunk_status_offset(layout.bitmap_offset as i64, current_complete_chunk as i64);
     let mut buf = [0; 1];
     for byte_num in first_byte..=last_byte {
-        file.seek(SeekFrom::End(byte_num)).unwrap();
-        file.read_exact(&mut buf).unwrap();
+        file.seek(SeekFrom::End(byte_num))?;
+        file.read_exact(&mut buf)?;
 
         let start_offset = if byte_num == first_byte {
             last_working_chunk % 8
@@ -206,28 +468,396 @@ fn set_written_chunks(path: &str, footer_space: u64, working_chunk_from_to: (u64
             byte |= 1 << (7 - bit_offset);
         }
 
-        file.seek(SeekFrom::End(byte_num)).unwrap();
-        file.write_all(&[byte]).unwrap();
+        file.seek(SeekFrom::End(byte_num))?;
+        file.write_all(&[byte])?;
     }
+    Ok(())
 }
 
-fn get_chunk_status_offset(footer_space: i64, chunk: i64) -> i64 {
-    -footer_space + (chunk / 8)
+/// Hashes data as it is written, one chunk at a time. Must start on a chunk boundary.
+struct ChunkHasher {
+    chunk_size: u64,
+    /// The chunk currently being hashed.
+    chunk: u64,
+    filled: u64,
+    hash: u32,
 }
 
-fn tmp_file_name(path: &str) -> String {
-    format!("{}.grapplepartial", path)
+impl ChunkHasher {
+    fn new(chunk_size: u64, first_byte: u64) -> ChunkHasher {
+        ChunkHasher {
+            chunk_size,
+            chunk: first_byte / chunk_size,
+            filled: 0,
+            hash: 0,
+        }
+    }
+
+    /// Hashes the next piece of data, returning the hashes of any chunks it completed.
+    fn update(&mut self, mut data: &[u8]) -> Vec<u32> {
+        let mut completed = vec![];
+        while !data.is_empty() {
+            let remaining = (self.chunk_size - self.filled) as usize;
+            let (head, tail) = data.split_at(remaining.min(data.len()));
+            self.hash = resume_helper::update_chunk_hash(self.hash, head);
+            self.filled += head.len() as u64;
+            data = tail;
+
+            if self.filled == self.chunk_size {
+                completed.push(self.hash);
+                self.chunk += 1;
+                self.filled = 0;
+                self.hash = 0;
+            }
+        }
+        completed
+    }
 }
 
-fn calculate_chunk_count_and_space(bytes: u64) -> (u64, usize) {
-    let mut num_chunks = bytes / CHUNK_SIZE_U64;
-    if bytes % CHUNK_SIZE_U64 > 0 {
-        num_chunks += 1;
+/// Notices a connection that stays below a minimum speed for a whole window.
+///
+/// Time spent sleeping for the bandwidth and rate limits doesn't count towards the window, so
+/// a limit below the minimum speed doesn't fail every connection.
+struct SpeedCheck {
+    low_speed: Option<(u64, Duration)>,
+    window_start: Instant,
+    window_bytes: u64,
+    window_paused: Duration,
+}
+
+impl SpeedCheck {
+    fn new(low_speed: Option<(u64, Duration)>) -> SpeedCheck {
+        SpeedCheck {
+            low_speed,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            window_paused: Duration::new(0, 0),
+        }
+    }
+
+    /// Leaves `paused` out of the current window.
+    fn pause(&mut self, paused: Duration) {
+        self.window_paused += paused;
+    }
+
+    fn update(&mut self, len: usize) -> Result<()> {
+        if let Some((bytes_per_second, window)) = self.low_speed {
+            self.window_bytes += len as u64;
+            let elapsed = self
+                .window_start
+                .elapsed()
+                .checked_sub(self.window_paused)
+                .unwrap_or_else(|| Duration::new(0, 0));
+            if elapsed < window {
+                return Ok(());
+            }
+
+            let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+            if self.window_bytes * 1000 < bytes_per_second * elapsed_ms {
+                return Err(GrappleError::Timeout(format!(
+                    "slower than {} B/s for {} seconds",
+                    bytes_per_second,
+                    window.as_secs()
+                )));
+            }
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+            self.window_paused = Duration::new(0, 0);
+        }
+        Ok(())
+    }
+}
+
+struct Throttle {
+    bandwidth: Option<f64>,
+    last_bw_sync: Instant,
+    bytes_since_bw_sync: f64,
+    bytes_between_bw_sync: f64,
+}
+
+impl Throttle {
+    fn new(thread_bandwidth: Option<u32>) -> Throttle {
+        let bandwidth = thread_bandwidth.map(|bw| f64::from(bw) * 1024_f64);
+        Throttle {
+            bandwidth,
+            last_bw_sync: Instant::now(),
+            bytes_since_bw_sync: 0.0,
+            bytes_between_bw_sync: bandwidth.unwrap_or(0.0) * 0.1,
+        }
+    }
+
+    /// Sleeps as needed to keep to the bandwidth, returning how long it slept.
+    fn wait(&mut self, len: usize) -> Duration {
+        let mut slept = Duration::new(0, 0);
+        if let Some(bw) = self.bandwidth {
+            self.bytes_since_bw_sync += len as f64;
+
+            if self.bytes_since_bw_sync >= self.bytes_between_bw_sync {
+                let seconds_wait = len as f64 / bw;
+                let wait_time = Duration::new(
+                    seconds_wait.trunc() as u64,
+                    (seconds_wait * 1_000_000_000_f64) as u32,
+                );
+                let time_passed = Instant::now() - self.last_bw_sync;
+
+                if wait_time.gt(&time_passed) {
+                    slept = wait_time - time_passed;
+                    thread::sleep(slept);
+                }
+
+                self.last_bw_sync = Instant::now();
+            }
+        }
+        slept
+    }
+}
+
+/// Reads the footer of an existing partial file for a download of `bytes`, returning its layout
+/// and stored validators, or `None` if there's no usable partial file.
+fn read_footer(tmp_name: &Path, bytes: u64) -> Result<Option<(Layout, Option<Validators>)>> {
+    let existing_file_length = match fs::metadata(tmp_name) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Ok(None),
+    };
+
+    if existing_file_length < bytes + 8 {
+        return Ok(None);
+    }
+
+    let mut file = File::open(tmp_name)?;
+    let mut trailer = [0_u8; 8];
+    file.seek(SeekFrom::End(-8))?;
+    file.read_exact(&mut trailer)?;
+    let trailing_u64 = resume_helper::be_u64(&trailer);
+
+    // Versioned footers end with their own length
+    if existing_file_length - bytes == trailing_u64 {
+        let mut footer = vec![0_u8; trailing_u64 as usize];
+        file.seek(SeekFrom::End(-(trailing_u64 as i64)))?;
+        file.read_exact(&mut footer)?;
+
+        if resume_helper::has_magic(&footer) {
+            let state = resume_helper::decode(&footer)?;
+            if state.total_length != bytes {
+                return Ok(None);
+            }
+
+            let layout = Layout::from_state(&state, ResumeStorage::Footer);
+            return Ok(Some((layout, Some(state.validators))));
+        }
+    }
+
+    // v0 footers are the bitmap followed by the chunk count
+    let (chunk_count, chunk_space) =
+        resume_helper::calculate_chunk_count_and_space(bytes, resume_helper::V0_CHUNK_SIZE);
+    if existing_file_length != bytes + chunk_space as u64 + 8 {
+        return Ok(None);
+    }
+
+    if trailing_u64 != chunk_count {
+        return Err(GrappleError::ResumeCorrupted(format!(
+            "expected {} chunks in the partial file footer but found {}",
+            chunk_count, trailing_u64
+        )));
+    }
+
+    let layout = Layout {
+        chunk_size: resume_helper::V0_CHUNK_SIZE,
+        bitmap_offset: chunk_space as u64 + 8,
+        hash_offset: None,
+        storage: ResumeStorage::Footer,
+    };
+    Ok(Some((layout, None)))
+}
+
+fn get_chunk_status_offset(bitmap_offset: i64, chunk: i64) -> i64 {
+    -bitmap_offset + (chunk / 8)
+}
+
+/// Reads the control file of a sidecar partial download, returning its layout and validators,
+/// or `None` if there's no usable partial download.
+fn read_sidecar(
+    tmp_name: &Path,
+    control_name: &Path,
+    bytes: u64,
+) -> Result<Option<(Layout, Option<Validators>)>> {
+    let data_file_length = fs::metadata(tmp_name).map(|metadata| metadata.len());
+    if data_file_length.ok() != Some(bytes) {
+        return Ok(None);
+    }
+
+    let mut state_block = vec![];
+    match File::open(control_name) {
+        Ok(mut file) => file.read_to_end(&mut state_block)?,
+        Err(_) => return Ok(None),
+    };
+
+    let state = resume_helper::decode(&state_block)?;
+    if state.total_length != bytes {
+        return Ok(None);
     }
-    let mut chunk_space = num_chunks / 8;
-    if num_chunks % 8 > 0 {
-        chunk_space += 1;
+
+    let layout = Layout::from_state(&state, ResumeStorage::Sidecar);
+    Ok(Some((layout, Some(state.validators))))
+}
+
+/// The file holding the chunk bitmap for `layout`.
+fn state_file_name(path: &Path, layout: Layout) -> PathBuf {
+    match layout.storage {
+        ResumeStorage::Footer => tmp_file_name(path),
+        ResumeStorage::Sidecar => control_file_name(path),
+    }
+}
+
+fn numbered_file_name(path: &Path, n: u32) -> PathBuf {
+    let mut file_name = path.file_stem().map_or_else(OsString::new, OsString::from);
+    file_name.push(format!(" ({})", n));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+fn control_file_name(path: &Path) -> PathBuf {
+    let mut control_name = OsString::from(path.as_os_str());
+    control_name.push(".grapple");
+    PathBuf::from(control_name)
+}
+
+pub fn tmp_file_name(path: &Path) -> PathBuf {
+    let mut tmp_name = OsString::from(path.as_os_str());
+    tmp_name.push(".grapplepartial");
+    PathBuf::from(tmp_name)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn speed_check_fails_slow_windows() {
+        let window = Duration::from_secs(1);
+        let mut speed_check = SpeedCheck {
+            low_speed: Some((100, window)),
+            window_start: Instant::now() - window,
+            window_bytes: 0,
+            window_paused: Duration::new(0, 0),
+        };
+        assert!(speed_check.update(50).is_err());
+
+        speed_check.window_start = Instant::now() - window;
+        speed_check.window_bytes = 0;
+        assert!(speed_check.update(150).is_ok());
+        assert!(speed_check.update(10).is_ok());
+
+        assert!(SpeedCheck::new(None).update(0).is_ok());
     }
 
-    (num_chunks, chunk_space as usize)
+    #[test]
+    fn speed_check_ignores_paused_time() {
+        let window = Duration::from_secs(1);
+        let mut speed_check = SpeedCheck {
+            low_speed: Some((100, window)),
+            window_start: Instant::now() - 2 * window,
+            window_bytes: 0,
+            window_paused: Duration::new(0, 0),
+        };
+        speed_check.pause(Duration::from_millis(1500));
+        assert!(speed_check.update(50).is_ok());
+        speed_check.pause(window);
+        assert!(speed_check.update(0).is_ok());
+    }
+
+    #[test]
+    fn resumes_v1_sidecar_without_hashes() {
+        let path = env::temp_dir().join(format!("grapple-v1-test-{}", process::id()));
+        let state = ResumeState {
+            version: 1,
+            chunk_size: 1024,
+            total_length: 4 * 1024,
+            validators: Validators {
+                url: "http://origin.com/file.txt".to_string(),
+                etag: None,
+                last_modified: None,
+            },
+        };
+        let mut state_block = resume_helper::encode(&state);
+        let bitmap_start = state_block.len() - state.bitmap_offset() as usize;
+        state_block[bitmap_start] = 0b1100_0000;
+        File::create(tmp_file_name(&path))
+            .unwrap()
+            .set_len(state.total_length)
+            .unwrap();
+        File::create(control_file_name(&path))
+            .unwrap()
+            .write_all(&state_block)
+            .unwrap();
+
+        let (layout, validators) = read_sidecar(
+            &tmp_file_name(&path),
+            &control_file_name(&path),
+            state.total_length,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(layout.hash_offset, None);
+        assert_eq!(validators, Some(state.validators));
+        assert_eq!(
+            get_first_empty_chunk(&path, layout, (0, state.total_length - 1)).unwrap(),
+            2 * 1024
+        );
+
+        fs::remove_file(tmp_file_name(&path)).unwrap();
+        fs::remove_file(control_file_name(&path)).unwrap();
+    }
+
+    #[test]
+    fn cleared_chunks_are_downloaded_again() {
+        let path = env::temp_dir().join(format!("grapple-clear-test-{}", process::id()));
+        let bytes = 3 * CHUNK_SIZE_U64;
+        let validators = Validators {
+            url: "http://origin.com/file.txt".to_string(),
+            etag: None,
+            last_modified: None,
+        };
+        let (layout, _) = create_file(&path, bytes, &validators, ResumeStorage::Footer).unwrap();
+        let hash = resume_helper::chunk_hash(&[0; CHUNK_SIZE_USIZE]);
+        set_chunk_hashes(&path, layout, 0, &[hash; 3]).unwrap();
+        set_written_chunks(&path, layout, (0, 3)).unwrap();
+        assert_eq!(
+            get_first_empty_chunk(&path, layout, (0, bytes - 1)).unwrap(),
+            bytes
+        );
+
+        // A rerun after a checksum mismatch picks up the same partial file and fetches all of it
+        clear_written_chunks(&path, layout, bytes).unwrap();
+        let (rerun_layout, restarted) =
+            create_file(&path, bytes, &validators, ResumeStorage::Footer).unwrap();
+        assert_eq!((rerun_layout, restarted), (layout, false));
+        assert_eq!(
+            get_first_empty_chunk(&path, rerun_layout, (0, bytes - 1)).unwrap(),
+            0
+        );
+
+        fs::remove_file(tmp_file_name(&path)).unwrap();
+    }
+
+    #[test]
+    fn numbered_file_names() {
+        assert_eq!(
+            numbered_file_name(Path::new("dir/file.txt"), 1),
+            PathBuf::from("dir/file (1).txt")
+        );
+        assert_eq!(
+            numbered_file_name(Path::new("archive.tar.gz"), 2),
+            PathBuf::from("archive.tar (2).gz")
+        );
+        assert_eq!(
+            numbered_file_name(Path::new("README"), 3),
+            PathBuf::from("README (3)")
+        );
+    }
 }
@@ -0,0 +1,61 @@
+//! Interruptible, segmented download engine behind the `grapple` command line tool.
+//!
+//! ```no_run
+//! extern crate grapple;
+//! extern crate url;
+//!
+//! use grapple::Downloader;
+//! use url::Url;
+//!
+//! let url = Url::parse("https://example.com/large.iso").unwrap();
+//! let handle = Downloader::new(url).threads(4).start().unwrap();
+//! println!("{:?} bytes to fetch", handle.progress().total());
+//! let download = handle.wait().unwrap();
+//! println!("Saved to {}", download.path().display());
+//! ```
+
+#![deny(
+    missing_debug_implementations,
+    missing_copy_implementations,
+    trivial_casts,
+    trivial_numeric_casts,
+    unsafe_code,
+    unused_import_braces,
+    unused_qualifications
+)]
+
+extern crate atty;
+extern crate base64;
+extern crate blake3;
+extern crate crc;
+extern crate md5;
+extern crate pbr;
+extern crate reqwest;
+extern crate rpassword;
+extern crate sha1;
+extern crate sha2;
+extern crate url;
+extern crate uuid;
+#[macro_use]
+extern crate lazy_static;
+
+mod auth_helper;
+mod checksum_helper;
+mod concurrency;
+mod credential_helper;
+mod downloader;
+mod error;
+mod file_helper;
+mod progress;
+mod rate_limit;
+mod request_helper;
+mod resume_helper;
+mod scheduler;
+mod ui_helper;
+
+pub use checksum_helper::{Checksum, ChecksumAlgorithm};
+pub use downloader::{Download, DownloadHandle, Downloader};
+pub use error::GrappleError;
+pub use file_helper::{ConflictPolicy, ResumeStorage};
+pub use progress::Progress;
+pub use rate_limit::parse_rate;
@@ -10,181 +10,223 @@
 
 #[macro_use]
 extern crate clap;
-extern crate base64;
-extern crate md5;
-extern crate pbr;
-extern crate reqwest;
+extern crate grapple;
 extern crate url;
-extern crate uuid;
-#[macro_use]
-extern crate lazy_static;
-
-mod auth_helper;
-mod file_helper;
-mod request_helper;
-mod ui_helper;
-
-use clap::App;
-use reqwest::header::{AcceptRanges, ContentLength, RangeUnit};
-use reqwest::Url;
-use std::ops::Deref;
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use clap::{App, ErrorKind};
+use grapple::{parse_rate, Checksum, ConflictPolicy, Downloader, GrappleError, ResumeStorage};
+use std::env;
+use std::fs;
+use std::process;
+use std::str::FromStr;
 use std::time::Duration;
-use std::{process, thread};
+use url::Url;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-lazy_static! {
-    static ref CURRENTLY_RUNNING_THREADS: AtomicUsize = AtomicUsize::new(0);
-    static ref HAS_FAILED: AtomicBool = AtomicBool::new(false);
-}
-
 fn main() {
     let yaml = load_yaml!("cli.yml");
-    let m = App::from_yaml(yaml).version(VERSION).get_matches();
+    let m = match App::from_yaml(yaml).version(VERSION).get_matches_safe() {
+        Ok(m) => m,
+        Err(ref e)
+            if e.kind == ErrorKind::HelpDisplayed || e.kind == ErrorKind::VersionDisplayed =>
+        {
+            e.exit()
+        }
+        // clap exits with 1 on usage errors, which would read as failed parts
+        Err(e) => {
+            eprintln!("{}", e.message);
+            process::exit(invalid_option(&e.message).exit_code());
+        }
+    };
 
     #[cfg_attr(feature = "clippy", allow(option_unwrap_used))]
     let raw_uri = m.value_of("uri").unwrap(); // Unwrap is safe - required by clap
-    let mut url = match Url::parse(raw_uri) {
+    let url = match Url::parse(raw_uri) {
         Ok(uri) => uri,
-        Err(e) => panic!("Couldn't parse URI: {}", e),
+        Err(e) => exit_with_error(&invalid_option(&format!("Couldn't parse URI: {}", e))),
     };
 
-    let file_name = request_helper::get_last_url_segment_decoded(&url);
-
-    if Path::new(&file_name).exists() {
-        println!(
-            "{} already exists, please remove it and try again.",
-            file_name
-        );
-        process::exit(1);
-    }
-
     let thread_count = m
         .value_of("thread_count")
-        .map(|tc| tc.parse::<usize>().expect("Failed to parse thread count."))
+        .map(|tc| parse_or_exit::<usize>(tc, "Failed to parse thread count."))
         .unwrap_or(10);
     let part_count = m
         .value_of("part_count")
-        .map(|tc| tc.parse::<usize>().expect("Failed to parse part count."))
+        .map(|tc| parse_or_exit::<usize>(tc, "Failed to parse part count."))
         .unwrap_or(thread_count);
-    let part_count_u64 = part_count as u64;
-
-    if part_count < thread_count {
-        panic!("Part count too low, must be at least the thread count.");
-    }
-
-    if thread_count > 20 {
-        panic!("Thread count too high, please select between 2 and 20 threads.");
-    } else if thread_count < 2 {
-        panic!("Thread count too low, please select between 2 and 20 threads.");
-    }
 
     let username = m
         .value_of("username")
-        .map(|u| u.parse::<String>().expect("Failed to parse username."));
+        .map(|u| u.to_string())
+        .or_else(|| env::var("GRAPPLE_USERNAME").ok());
     let password = m
         .value_of("password")
-        .map(|p| p.parse::<String>().expect("Failed to parse password."));
+        .map(|p| p.to_string())
+        .or_else(|| env::var("GRAPPLE_PASSWORD").ok());
+    let netrc_file = m.value_of("netrc_file");
+    let credential_helper = m
+        .value_of("credential_helper")
+        .map(|c| c.to_string())
+        .or_else(|| env::var("GRAPPLE_CREDENTIAL_HELPER").ok());
+
+    let bearer = m
+        .value_of("bearer")
+        .map(|token| token.to_string())
+        .or_else(|| {
+            m.value_of("bearer_file")
+                .map(|file| match fs::read_to_string(file) {
+                    Ok(token) => token.trim().to_string(),
+                    Err(e) => exit_with_error(&invalid_option(&format!(
+                        "Couldn't read bearer token file {}: {}",
+                        file, e
+                    ))),
+                })
+        });
+
+    let headers: Vec<(String, String)> = m
+        .values_of("header")
+        .map(|headers| headers.map(parse_header_or_exit).collect())
+        .unwrap_or_default();
+
+    let thread_bandwidth = m
+        .value_of("thread_bandwidth")
+        .map(|bw| parse_or_exit::<u32>(bw, "Failed to parse thread bandwidth."));
+
+    let rate_limit = m
+        .value_of("limit_rate")
+        .map(|rate| parse_rate(rate).unwrap_or_else(|e| exit_with_error(&e)));
 
-    request_helper::override_username_password(&mut url, username, password);
+    let retries = m
+        .value_of("retries")
+        .map(|retries| parse_or_exit::<u32>(retries, "Failed to parse retry count."));
 
-    let thread_bandwidth = m.value_of("thread_bandwidth").map(|bw| {
-        bw.parse::<u32>()
-            .expect("Failed to parse thread bandwidth.")
+    let timeout = m
+        .value_of("timeout")
+        .map(|secs| parse_or_exit::<u64>(secs, "Failed to parse timeout."));
+
+    let low_speed = m.value_of("speed_limit").map(|limit| {
+        let limit = parse_or_exit::<u64>(limit, "Failed to parse speed limit.");
+        let time = m.value_of("speed_time").map_or(30, |secs| {
+            parse_or_exit::<u64>(secs, "Failed to parse speed time.")
+        });
+        (limit, time)
     });
 
-    let url = url;
-    let res = request_helper::head_request(url.clone());
-    let headers = res.headers();
-    let has_range_header = headers.get::<AcceptRanges>().map_or(false, |range_header| {
-        range_header.deref().contains(&RangeUnit::Bytes)
+    let checksum = m.value_of("checksum").map(|checksum| {
+        checksum
+            .parse::<Checksum>()
+            .unwrap_or_else(|e| exit_with_error(&e))
     });
-    if !has_range_header {
-        panic!("Requested resource does not allow Range requests!")
+
+    let resume_storage = if m.is_present("sidecar") {
+        ResumeStorage::Sidecar
+    } else {
+        ResumeStorage::Footer
+    };
+
+    let on_conflict = match m.value_of("on_conflict") {
+        Some("overwrite") => ConflictPolicy::Overwrite,
+        Some("rename") => ConflictPolicy::Rename,
+        Some("skip") => ConflictPolicy::Skip,
+        Some("skip-if-identical") => ConflictPolicy::SkipIfIdentical,
+        _ => ConflictPolicy::Fail,
+    };
+
+    let output = m.value_of("output");
+    let dir = m.value_of("dir");
+
+    let mut downloader = Downloader::new(url)
+        .threads(thread_count)
+        .adaptive(m.is_present("adaptive"))
+        .parts(part_count)
+        .credentials(username, password)
+        .resume_storage(resume_storage)
+        .on_conflict(on_conflict)
+        .etag_md5(m.is_present("etag_md5"))
+        .prompt_for_password(true)
+        .progress_bars(true);
+
+    if let Some(bw) = thread_bandwidth {
+        downloader = downloader.thread_bandwidth(bw);
     }
 
-    let content_length = headers
-        .get::<ContentLength>()
-        .map_or(0, |length_header| *length_header.deref());
+    if let Some(rate) = rate_limit {
+        downloader = downloader.limit_rate(rate);
+    }
 
-    if content_length < 1024 {
-        panic!("Content too small");
+    if let Some(retries) = retries {
+        downloader = downloader.retries(retries);
     }
 
-    let part_length = (content_length / part_count_u64) / file_helper::CHUNK_SIZE_U64
-        * file_helper::CHUNK_SIZE_U64;
+    if let Some(secs) = timeout {
+        downloader = downloader.timeout(Duration::from_secs(secs));
+    }
 
-    let mut sections: Vec<(u64, u64)> = vec![];
-    let mut lengths: Vec<u64> = vec![];
-    for section in 0..(part_count_u64 - 1) {
-        sections.push((section * part_length, (section + 1) * part_length - 1));
-        lengths.push(part_length);
+    if let Some((limit, secs)) = low_speed {
+        downloader = downloader.low_speed_limit(limit, Duration::from_secs(secs));
     }
-    sections.push(((part_count_u64 - 1) * part_length, content_length - 1));
-    lengths.push(content_length - (part_count_u64 - 1) * part_length);
-
-    ui_helper::start_pbr(&file_name, lengths);
-
-    let footer_space = file_helper::create_file(&file_name, content_length);
-    let mut children = vec![];
-    for (child_id, section) in sections.into_iter().enumerate() {
-        let url_clone = url.clone();
-        let file_name_clone = file_name.clone();
-        loop {
-            {
-                let currently_running = CURRENTLY_RUNNING_THREADS.load(Ordering::Acquire);
-                if currently_running < thread_count {
-                    CURRENTLY_RUNNING_THREADS.store(currently_running + 1, Ordering::Release);
-                    break;
-                }
-            }
-            thread::sleep(Duration::new(1, 0));
-        }
-        let child = thread::spawn(move || {
-            ui_helper::setting_up_bar(child_id);
-            let start = file_helper::get_first_empty_chunk(&file_name_clone, footer_space, section);
-            if start <= section.1 {
-                let prefilled = start - section.0;
-                let section = (start, section.1);
-                let range_req = request_helper::get_range_request(url_clone.clone(), section);
-                ui_helper::start_bar(child_id);
-                let written = file_helper::save_response(
-                    &file_name_clone,
-                    range_req,
-                    footer_space,
-                    child_id,
-                    prefilled,
-                    thread_bandwidth,
-                ).unwrap();
-                if written > 0 {
-                    ui_helper::success_bar(child_id);
-                } else {
-                    ui_helper::fail_bar(child_id);
-                    HAS_FAILED.store(true, Ordering::Release);
-                }
-            } else {
-                ui_helper::update_bar(child_id, section.1 - section.0 + 1);
-                ui_helper::success_bar(child_id);
-            }
-
-            CURRENTLY_RUNNING_THREADS.store(
-                CURRENTLY_RUNNING_THREADS.load(Ordering::Acquire) - 1,
-                Ordering::Release,
-            );
-        });
-        children.push(child);
+
+    if let Some(output) = output {
+        downloader = downloader.output(output);
     }
-    for child in children {
-        let _ = child.join();
+
+    if let Some(dir) = dir {
+        downloader = downloader.dir(dir);
     }
 
-    if HAS_FAILED.load(Ordering::Acquire) {
-        println!("Some parts failed to download, please rerun.");
-        process::exit(1);
-    } else {
-        ui_helper::success_global_bar();
-        file_helper::remove_footer_and_save(&file_name, content_length);
+    downloader = match netrc_file {
+        Some(netrc_file) => downloader.netrc_file(netrc_file),
+        None => downloader.netrc(),
+    };
+
+    if let Some(command) = credential_helper {
+        downloader = downloader.credential_helper(command);
+    }
+
+    if let Some(token) = bearer {
+        downloader = downloader.bearer_token(token);
+    }
+
+    for (name, value) in headers {
+        downloader = downloader.header(name, value);
+    }
+
+    if let Some(checksum) = checksum {
+        downloader = downloader.checksum(checksum);
+    }
+
+    match downloader.download() {
+        Ok(ref download) if download.skipped() => {
+            println!("{} already exists, skipping.", download.path().display())
+        }
+        Ok(_) => {}
+        Err(e) => exit_with_error(&e),
     }
 }
+
+fn parse_or_exit<T: FromStr>(value: &str, message: &str) -> T {
+    value
+        .parse::<T>()
+        .unwrap_or_else(|_| exit_with_error(&invalid_option(message)))
+}
+
+fn parse_header_or_exit(header: &str) -> (String, String) {
+    let mut parts = header.splitn(2, ':');
+    match (parts.next().map(str::trim), parts.next().map(str::trim)) {
+        (Some(name), Some(value)) if !name.is_empty() => (name.to_string(), value.to_string()),
+        _ => exit_with_error(&invalid_option(&format!(
+            "Couldn't parse header {}, expected \"Name: value\".",
+            header
+        ))),
+    }
+}
+
+fn invalid_option(message: &str) -> GrappleError {
+    GrappleError::InvalidOption(message.to_string())
+}
+
+fn exit_with_error(e: &GrappleError) -> ! {
+    eprintln!("{}", e);
+    process::exit(e.exit_code());
+}
@@ -0,0 +1,135 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use ui_helper::{Bars, Counter, Display};
+
+/// Shared view of how far a download has got, cheap to clone and safe to poll from any thread.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    parts: Arc<Mutex<Vec<u64>>>,
+    total: Option<u64>,
+    display: Arc<Display>,
+}
+
+impl Progress {
+    pub(crate) fn new(file_name: &str, lengths: Vec<u64>, bars: bool) -> Progress {
+        let total = lengths.iter().sum();
+        let parts = vec![0; lengths.len()];
+        let display = if bars {
+            Display::Bars(Bars::start(file_name, lengths))
+        } else {
+            Display::Hidden
+        };
+
+        Progress {
+            parts: Arc::new(Mutex::new(parts)),
+            total: Some(total),
+            display: Arc::new(display),
+        }
+    }
+
+    /// Progress for a single stream of unknown length, drawn as a byte counter.
+    pub(crate) fn unknown_length(file_name: &str, bars: bool) -> Progress {
+        let display = if bars {
+            Display::Counter(Counter::start(file_name))
+        } else {
+            Display::Hidden
+        };
+
+        Progress {
+            parts: Arc::new(Mutex::new(vec![0])),
+            total: None,
+            display: Arc::new(display),
+        }
+    }
+
+    /// Total number of bytes in the download, if the server reported it.
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    /// Number of bytes on disk so far, including bytes recovered from a previous run.
+    pub fn downloaded(&self) -> u64 {
+        self.lock_parts().iter().sum()
+    }
+
+    /// Bytes on disk for each part, in part order.
+    pub fn parts(&self) -> Vec<u64> {
+        self.lock_parts().clone()
+    }
+
+    pub(crate) fn setting_up(&self, part: usize) {
+        if let Display::Bars(ref bars) = *self.display {
+            bars.setting_up(part);
+        }
+    }
+
+    pub(crate) fn stalled(&self, part: usize) {
+        if let Display::Bars(ref bars) = *self.display {
+            bars.stalled(part);
+        }
+    }
+
+    pub(crate) fn start(&self, part: usize) {
+        if let Display::Bars(ref bars) = *self.display {
+            bars.start_part(part);
+        }
+    }
+
+    pub(crate) fn update(&self, part: usize, bytes: u64) {
+        let mut parts = self.lock_parts();
+        parts[part] = bytes;
+        match *self.display {
+            Display::Bars(ref bars) => bars.update(part, bytes, parts.iter().sum()),
+            Display::Counter(ref counter) => counter.update(bytes),
+            Display::Hidden => {}
+        }
+    }
+
+    /// Adds to a part that several workers may be filling at once.
+    pub(crate) fn advance(&self, part: usize, bytes: u64) {
+        let mut parts = self.lock_parts();
+        parts[part] += bytes;
+        self.draw_part(&parts, part);
+    }
+
+    /// Takes back bytes of a part that have to be downloaded again.
+    pub(crate) fn rewind(&self, part: usize, bytes: u64) {
+        let mut parts = self.lock_parts();
+        parts[part] = parts[part].saturating_sub(bytes);
+        self.draw_part(&parts, part);
+    }
+
+    pub(crate) fn succeed(&self, part: usize) {
+        if let Display::Bars(ref bars) = *self.display {
+            bars.succeed(part);
+        }
+    }
+
+    pub(crate) fn fail(&self, part: usize) {
+        match *self.display {
+            Display::Bars(ref bars) => bars.fail(part),
+            Display::Counter(ref counter) => counter.finish("Download Failed!"),
+            Display::Hidden => {}
+        }
+    }
+
+    pub(crate) fn complete(&self) {
+        match *self.display {
+            Display::Bars(ref bars) => bars.complete(),
+            Display::Counter(ref counter) => counter.finish("Download Complete!"),
+            Display::Hidden => {}
+        }
+    }
+
+    /// Redraws a part's bar, called with the parts still locked so bars move in order.
+    fn draw_part(&self, parts: &[u64], part: usize) {
+        if let Display::Bars(ref bars) = *self.display {
+            bars.update(part, parts[part], parts.iter().sum());
+        }
+    }
+
+    fn lock_parts(&self) -> MutexGuard<Vec<u64>> {
+        self.parts
+            .lock()
+            .expect("Failed to acquire progress lock, lock poisoned!")
+    }
+}
@@ -0,0 +1,138 @@
+use error::{GrappleError, Result};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Most a connection can send in one go after the download has been idle, in seconds of rate.
+const BURST_SECS: f64 = 0.25;
+
+/// Token bucket shared by every connection of a download, capping their combined speed.
+///
+/// Connections take what they read and go into debt rather than waiting for the bucket to fill,
+/// so large reads aren't starved and the average still comes out at the rate.
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    rate: Option<u64>,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter for `rate` bytes per second, `None` or zero for no limit.
+    pub fn new(rate: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            state: Mutex::new(State {
+                rate: rate.filter(|&rate| rate > 0),
+                tokens: 0.0,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Changes the rate of a running download, `None` or zero lifts the limit.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.lock_state();
+        state.rate = rate.filter(|&rate| rate > 0);
+        state.tokens = 0.0;
+        state.refilled_at = Instant::now();
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.lock_state().rate
+    }
+
+    /// Accounts for `bytes` just received, sleeping until they fit within the rate. Returns how
+    /// long it slept.
+    pub fn take(&self, bytes: usize) -> Duration {
+        let wait = {
+            let mut state = self.lock_state();
+            let rate = match state.rate {
+                Some(rate) => rate as f64,
+                None => return Duration::new(0, 0),
+            };
+
+            let now = Instant::now();
+            let elapsed = now - state.refilled_at;
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            state.tokens = (state.tokens + elapsed * rate).min(rate * BURST_SECS);
+            state.refilled_at = now;
+            state.tokens -= bytes as f64;
+            if state.tokens >= 0.0 {
+                return Duration::new(0, 0);
+            }
+            -state.tokens / rate
+        };
+
+        let wait = Duration::new(wait.trunc() as u64, (wait.fract() * 1e9) as u32);
+        thread::sleep(wait);
+        wait
+    }
+
+    fn lock_state(&self) -> MutexGuard<State> {
+        self.state
+            .lock()
+            .expect("Failed to acquire rate limit lock, lock poisoned!")
+    }
+}
+
+/// Parses a rate in bytes per second with an optional `K`, `M` or `G` suffix in powers of 1024,
+/// e.g. `500K` or `1.5M`.
+pub fn parse_rate(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024_f64),
+        Some('M') => (&value[..value.len() - 1], 1024_f64 * 1024_f64),
+        Some('G') => (&value[..value.len() - 1], 1024_f64 * 1024_f64 * 1024_f64),
+        _ => (value, 1_f64),
+    };
+
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite())
+        .map(|number| (number * multiplier) as u64)
+        .filter(|&rate| rate > 0)
+        .ok_or_else(|| {
+            GrappleError::InvalidOption(format!(
+                "Invalid rate {}, expected bytes per second such as 500K or 20M.",
+                value
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parses_human_rates() {
+        assert_eq!(parse_rate("100").unwrap(), 100);
+        assert_eq!(parse_rate("500K").unwrap(), 512_000);
+        assert_eq!(parse_rate("1.5k").unwrap(), 1536);
+        assert_eq!(parse_rate("20M").unwrap(), 20_971_520);
+        assert_eq!(parse_rate("1G").unwrap(), 1_073_741_824);
+        assert!(parse_rate("").is_err());
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-5K").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn take_waits_out_debt() {
+        let limiter = RateLimiter::new(Some(10_000));
+        let started = Instant::now();
+        let slept = limiter.take(1000);
+        assert!(slept >= Duration::from_millis(90));
+        assert!(started.elapsed() >= slept);
+
+        limiter.set_rate(None);
+        assert_eq!(limiter.rate(), None);
+        assert_eq!(limiter.take(1_000_000), Duration::new(0, 0));
+    }
+}
@@ -0,0 +1,352 @@
+//! Resume state kept alongside a partial download.
+//!
+//! The state block is laid out as follows, all integers big endian:
+//!
+//! | Field        | Size           | Notes                                           |
+//! |--------------|----------------|-------------------------------------------------|
+//! | magic        | 8              | `GRPLPART`                                      |
+//! | version      | 4              | `VERSION`                                       |
+//! | chunk size   | 8              |                                                 |
+//! | total length | 8              | length of the finished file                     |
+//! | metadata len | 4              |                                                 |
+//! | metadata     | metadata len   | source URL, ETag and Last-Modified, `\n` split  |
+//! | chunk hashes | 4 * chunks     | CRC32C of each chunk, valid once its bit is set |
+//! | bitmap       | ceil(chunks/8) | one bit per chunk, set once it's on disk        |
+//! | checksum     | 4              | CRC32 of the header and metadata                |
+//! | state len    | 8              | length of the whole block, including this       |
+//!
+//! Ending with the block length lets the block be found from the end of a file. Partial files
+//! written before the block was versioned (v0) hold just the bitmap followed by the chunk count,
+//! with 128 KiB chunks. Neither v0 files nor v1 blocks have chunk hashes, and they're still
+//! resumed without them.
+
+use crc::crc32;
+use error::{GrappleError, Result};
+use request_helper::Validators;
+use std::mem::transmute;
+use std::str;
+
+pub const VERSION: u32 = 2;
+/// First version to record chunk hashes.
+pub const HASHES_VERSION: u32 = 2;
+pub const TRAILER_LEN: u64 = 12;
+pub const V0_CHUNK_SIZE: u64 = 128 * 1024;
+
+const MAGIC: &[u8; 8] = b"GRPLPART";
+const HEADER_LEN: usize = 32;
+const HASH_LEN: u64 = 4;
+
+/// Everything recorded about a partial download, other than which chunks are done.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResumeState {
+    pub version: u32,
+    pub chunk_size: u64,
+    pub total_length: u64,
+    pub validators: Validators,
+}
+
+impl ResumeState {
+    /// Distance from the end of the state block to the start of its bitmap.
+    pub fn bitmap_offset(&self) -> u64 {
+        let (_, chunk_space) = calculate_chunk_count_and_space(self.total_length, self.chunk_size);
+        chunk_space as u64 + TRAILER_LEN
+    }
+
+    /// Distance from the end of the state block to the start of its chunk hashes, `None` for
+    /// versions without them.
+    pub fn hash_offset(&self) -> Option<u64> {
+        if self.version < HASHES_VERSION {
+            return None;
+        }
+
+        let (chunk_count, _) = calculate_chunk_count_and_space(self.total_length, self.chunk_size);
+        Some(self.bitmap_offset() + chunk_count * HASH_LEN)
+    }
+}
+
+/// Serialises the state with an empty bitmap and zeroed chunk hashes, if its version has them.
+pub fn encode(state: &ResumeState) -> Vec<u8> {
+    let metadata = encode_validators(&state.validators);
+    let (_, chunk_space) = calculate_chunk_count_and_space(state.total_length, state.chunk_size);
+    let hash_space = hash_space(state.version, state.total_length, state.chunk_size);
+
+    let mut buf: Vec<u8> =
+        Vec::with_capacity(HEADER_LEN + metadata.len() + hash_space + chunk_space + 12);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&u32_to_be_bytes(state.version));
+    buf.extend_from_slice(&u64_to_be_bytes(state.chunk_size));
+    buf.extend_from_slice(&u64_to_be_bytes(state.total_length));
+    buf.extend_from_slice(&u32_to_be_bytes(metadata.len() as u32));
+    buf.extend_from_slice(&metadata);
+
+    let checksum = crc32::checksum_ieee(&buf);
+    buf.resize(buf.len() + hash_space + chunk_space, 0);
+    buf.extend_from_slice(&u32_to_be_bytes(checksum));
+    let state_len = buf.len() as u64 + 8;
+    buf.extend_from_slice(&u64_to_be_bytes(state_len));
+    buf
+}
+
+pub fn has_magic(block: &[u8]) -> bool {
+    block.len() >= MAGIC.len() && &block[..MAGIC.len()] == MAGIC
+}
+
+/// Parses a whole state block, as produced by `encode` and filled in since.
+pub fn decode(block: &[u8]) -> Result<ResumeState> {
+    if !has_magic(block) || block.len() < HEADER_LEN + TRAILER_LEN as usize {
+        return Err(corrupted("the resume state is not recognised"));
+    }
+
+    let version = be_u32(&block[8..12]);
+    if version > VERSION {
+        return Err(corrupted(&format!(
+            "the resume state was written by a newer version of grapple (format {})",
+            version
+        )));
+    } else if version == 0 {
+        return Err(corrupted("unknown resume state format 0"));
+    }
+
+    let chunk_size = be_u64(&block[12..20]);
+    let total_length = be_u64(&block[20..28]);
+    let metadata_end = HEADER_LEN + be_u32(&block[28..32]) as usize;
+    if chunk_size == 0 {
+        return Err(corrupted("the resume state has a chunk size of zero"));
+    }
+
+    let (_, chunk_space) = calculate_chunk_count_and_space(total_length, chunk_size);
+    let checksum_start = metadata_end + hash_space(version, total_length, chunk_size) + chunk_space;
+    if block.len() as u64 != checksum_start as u64 + TRAILER_LEN {
+        return Err(corrupted(
+            "the resume state length doesn't match its contents",
+        ));
+    }
+
+    if crc32::checksum_ieee(&block[..metadata_end]) != be_u32(&block[checksum_start..]) {
+        return Err(corrupted("the resume state checksum doesn't match"));
+    }
+
+    let validators = decode_validators(&block[HEADER_LEN..metadata_end])
+        .ok_or_else(|| corrupted("the resume state metadata is unreadable"))?;
+
+    Ok(ResumeState {
+        version,
+        chunk_size,
+        total_length,
+        validators,
+    })
+}
+
+pub fn calculate_chunk_count_and_space(bytes: u64, chunk_size: u64) -> (u64, usize) {
+    let mut num_chunks = bytes / chunk_size;
+    if bytes % chunk_size > 0 {
+        num_chunks += 1;
+    }
+    let mut chunk_space = num_chunks / 8;
+    if num_chunks % 8 > 0 {
+        chunk_space += 1;
+    }
+
+    (num_chunks, chunk_space as usize)
+}
+
+/// CRC32C of a chunk's data, as stored in the chunk hashes.
+pub fn chunk_hash(data: &[u8]) -> u32 {
+    update_chunk_hash(0, data)
+}
+
+/// Extends a chunk hash over more of the chunk's data.
+pub fn update_chunk_hash(hash: u32, data: &[u8]) -> u32 {
+    crc32::update(hash, &crc32::CASTAGNOLI_TABLE, data)
+}
+
+pub fn be_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0_u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+
+    #[allow(unsafe_code)]
+    let value = u64::from_be(unsafe { transmute(buf) });
+    value
+}
+
+pub fn u64_to_be_bytes(value: u64) -> [u8; 8] {
+    #[allow(unsafe_code)]
+    let bytes: [u8; 8] = unsafe { transmute(value.to_be()) };
+    bytes
+}
+
+pub fn be_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0_u8; 4];
+    buf.copy_from_slice(&bytes[..4]);
+
+    #[allow(unsafe_code)]
+    let value = u32::from_be(unsafe { transmute(buf) });
+    value
+}
+
+pub fn u32_to_be_bytes(value: u32) -> [u8; 4] {
+    #[allow(unsafe_code)]
+    let bytes: [u8; 4] = unsafe { transmute(value.to_be()) };
+    bytes
+}
+
+/// Bytes taken by the chunk hashes of a block of `version`.
+fn hash_space(version: u32, total_length: u64, chunk_size: u64) -> usize {
+    if version < HASHES_VERSION {
+        return 0;
+    }
+
+    let (chunk_count, _) = calculate_chunk_count_and_space(total_length, chunk_size);
+    (chunk_count * HASH_LEN) as usize
+}
+
+fn corrupted(message: &str) -> GrappleError {
+    GrappleError::ResumeCorrupted(message.to_string())
+}
+
+fn encode_validators(validators: &Validators) -> Vec<u8> {
+    format!(
+        "{}\n{}\n{}",
+        validators.url,
+        validators.etag.as_ref().map_or("", |etag| etag.as_str()),
+        validators
+            .last_modified
+            .as_ref()
+            .map_or("", |last_modified| last_modified.as_str())
+    )
+    .into_bytes()
+}
+
+fn decode_validators(metadata: &[u8]) -> Option<Validators> {
+    let metadata = str::from_utf8(metadata).ok()?;
+    let mut fields = metadata.splitn(3, '\n');
+    let url = fields.next()?.to_string();
+    let mut next_field = || {
+        fields
+            .next()
+            .filter(|field| !field.is_empty())
+            .map(|field| field.to_string())
+    };
+    let etag = next_field();
+    let last_modified = next_field();
+
+    Some(Validators {
+        url,
+        etag,
+        last_modified,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn state() -> ResumeState {
+        ResumeState {
+            version: VERSION,
+            chunk_size: 1024,
+            total_length: 20 * 1024 + 1,
+            validators: Validators {
+                url: "http://origin.com/file.txt".to_string(),
+                etag: Some("\"abc\"".to_string()),
+                last_modified: None,
+            },
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let block = encode(&state());
+        assert!(has_magic(&block));
+        assert_eq!(be_u64(&block[block.len() - 8..]), block.len() as u64);
+        assert_eq!(decode(&block).unwrap(), state());
+    }
+
+    #[test]
+    fn bitmap_offset_points_at_bitmap() {
+        let block = encode(&state());
+        // 21 chunks need 3 bytes of bitmap, all empty
+        assert_eq!(state().bitmap_offset(), 3 + TRAILER_LEN);
+        let bitmap_start = block.len() - state().bitmap_offset() as usize;
+        assert_eq!(&block[bitmap_start..bitmap_start + 3], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn hash_offset_points_at_hashes() {
+        let block = encode(&state());
+        assert_eq!(
+            state().hash_offset(),
+            Some(state().bitmap_offset() + 21 * 4)
+        );
+        let hashes_start = block.len() - state().hash_offset().unwrap() as usize;
+        let metadata_len = be_u32(&block[28..32]) as usize;
+        assert_eq!(hashes_start, HEADER_LEN + metadata_len);
+    }
+
+    #[test]
+    fn decode_ignores_bitmap_and_hash_changes() {
+        let mut block = encode(&state());
+        let bitmap_start = block.len() - state().bitmap_offset() as usize;
+        let hashes_start = block.len() - state().hash_offset().unwrap() as usize;
+        block[bitmap_start] = 0b1110_0000;
+        block[hashes_start] = 0xff;
+        assert_eq!(decode(&block).unwrap(), state());
+    }
+
+    #[test]
+    fn decode_v1_without_hashes() {
+        let v1_state = ResumeState {
+            version: 1,
+            ..state()
+        };
+        let block = encode(&v1_state);
+        assert_eq!(decode(&block).unwrap(), v1_state);
+        assert_eq!(v1_state.hash_offset(), None);
+
+        // Only the header, metadata, bitmap and trailer
+        let metadata_len = be_u32(&block[28..32]) as usize;
+        let bitmap_start = block.len() - v1_state.bitmap_offset() as usize;
+        assert_eq!(bitmap_start, HEADER_LEN + metadata_len);
+    }
+
+    #[test]
+    fn decode_rejects_bad_checksum() {
+        let mut block = encode(&state());
+        block[HEADER_LEN] ^= 1;
+        assert!(decode(&block).is_err());
+    }
+
+    #[test]
+    fn decode_rejects_newer_version() {
+        let mut block = encode(&state());
+        block[11] = 3;
+        assert!(decode(&block).is_err());
+    }
+
+    #[test]
+    fn decode_rejects_missing_magic() {
+        let mut block = encode(&state());
+        block[0] = b'X';
+        assert!(!has_magic(&block));
+        assert!(decode(&block).is_err());
+    }
+
+    #[test]
+    fn chunk_hash_is_incremental() {
+        let data = b"123456789";
+        // CRC32C check value
+        assert_eq!(chunk_hash(data), 0xe306_9283);
+        assert_eq!(
+            update_chunk_hash(chunk_hash(&data[..4]), &data[4..]),
+            chunk_hash(data)
+        );
+    }
+
+    #[test]
+    fn chunk_count_and_space() {
+        assert_eq!(calculate_chunk_count_and_space(0, 1024), (0, 0));
+        assert_eq!(calculate_chunk_count_and_space(1024, 1024), (1, 1));
+        assert_eq!(calculate_chunk_count_and_space(8 * 1024 + 1, 1024), (9, 2));
+    }
+}
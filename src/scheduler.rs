@@ -0,0 +1,315 @@
+use std::cmp;
+use std::sync::{Mutex, MutexGuard};
+
+/// A byte range handed to a worker.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Assignment {
+    pub id: usize,
+    pub part: usize,
+    pub start: u64,
+    /// Inclusive end when the range was handed out, it may since have been split off.
+    pub end: u64,
+}
+
+/// Hands byte ranges to workers, splitting the largest unfinished range when a worker goes idle
+/// so no connection is left crawling through the end of a download alone.
+///
+/// Ranges are split on chunk boundaries, so every worker still writes whole chunks.
+#[derive(Debug)]
+pub struct Scheduler {
+    chunk_size: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    ranges: Vec<Range>,
+    /// Unfinished ranges per part.
+    outstanding: Vec<usize>,
+    failed: Vec<bool>,
+}
+
+#[derive(Debug)]
+struct Range {
+    part: usize,
+    /// Next byte to be written.
+    cursor: u64,
+    end: u64,
+    status: Status,
+}
+
+#[derive(Debug, PartialEq)]
+enum Status {
+    Pending,
+    Active,
+    Done,
+}
+
+impl Scheduler {
+    pub fn new(chunk_size: u64, part_count: usize) -> Scheduler {
+        Scheduler {
+            chunk_size,
+            state: Mutex::new(State {
+                ranges: vec![],
+                outstanding: vec![0; part_count],
+                failed: vec![false; part_count],
+            }),
+        }
+    }
+
+    /// Queues the inclusive range `start..=end` of `part`.
+    pub fn push(&self, part: usize, start: u64, end: u64) {
+        let mut state = self.lock_state();
+        state.outstanding[part] += 1;
+        state.ranges.push(Range {
+            part,
+            cursor: start,
+            end,
+            status: Status::Pending,
+        });
+    }
+
+    /// The next range for an idle worker, either a queued one or the back half of the largest
+    /// range in progress. `None` once nothing is left that's worth splitting.
+    pub fn next(&self) -> Option<Assignment> {
+        let mut state = self.lock_state();
+
+        if let Some(id) = state
+            .ranges
+            .iter()
+            .position(|range| range.status == Status::Pending)
+        {
+            let range = &mut state.ranges[id];
+            range.status = Status::Active;
+            return Some(Assignment {
+                id,
+                part: range.part,
+                start: range.cursor,
+                end: range.end,
+            });
+        }
+
+        let chunk_size = self.chunk_size;
+        let (id, split) = state
+            .ranges
+            .iter()
+            .enumerate()
+            .filter(|&(_, range)| range.status == Status::Active)
+            .filter_map(|(id, range)| {
+                split_point(range.cursor, range.end, chunk_size)
+                    .map(|split| (id, split, range.end + 1 - range.cursor))
+            })
+            .max_by_key(|&(_, _, remaining)| remaining)
+            .map(|(id, split, _)| (id, split))?;
+
+        let (part, end) = {
+            let range = &mut state.ranges[id];
+            let end = range.end;
+            range.end = split - 1;
+            (range.part, end)
+        };
+        state.outstanding[part] += 1;
+        state.ranges.push(Range {
+            part,
+            cursor: split,
+            end,
+            status: Status::Active,
+        });
+
+        Some(Assignment {
+            id: state.ranges.len() - 1,
+            part,
+            start: split,
+            end,
+        })
+    }
+
+    /// Claims up to `len` more bytes of a range for writing, returning how many may be written.
+    /// Fewer than `len` means the range ends within them.
+    pub fn claim(&self, id: usize, len: u64) -> u64 {
+        let mut state = self.lock_state();
+        let range = &mut state.ranges[id];
+        let allowed = cmp::min(len, (range.end + 1).saturating_sub(range.cursor));
+        range.cursor += allowed;
+        allowed
+    }
+
+    /// Current inclusive end of a range.
+    pub fn end(&self, id: usize) -> u64 {
+        self.lock_state().ranges[id].end
+    }
+
+    /// Moves a range back to `start` to retry it, returning how many claimed bytes that gives
+    /// up and the assignment to retry, or `None` if nothing is missing up to its end.
+    pub fn resume(&self, id: usize, start: u64) -> (u64, Option<Assignment>) {
+        let mut state = self.lock_state();
+        let range = &mut state.ranges[id];
+        let start = cmp::min(start, range.end + 1);
+        let rewound = range.cursor.saturating_sub(start);
+        range.cursor = start;
+        if start > range.end {
+            return (rewound, None);
+        }
+
+        let assignment = Assignment {
+            id,
+            part: range.part,
+            start,
+            end: range.end,
+        };
+        (rewound, Some(assignment))
+    }
+
+    /// Whether every byte of a range has been claimed.
+    pub fn is_complete(&self, id: usize) -> bool {
+        let state = self.lock_state();
+        let range = &state.ranges[id];
+        range.cursor > range.end
+    }
+
+    /// Marks a range finished, returning whether that was the last one of its part.
+    pub fn finish(&self, id: usize) -> bool {
+        let mut state = self.lock_state();
+        let part = state.ranges[id].part;
+        state.ranges[id].status = Status::Done;
+        state.outstanding[part] -= 1;
+        state.outstanding[part] == 0 && !state.failed[part]
+    }
+
+    /// Gives up on the rest of a range after a failure, it's left for a rerun to resume.
+    pub fn abandon(&self, id: usize) {
+        let mut state = self.lock_state();
+        let part = state.ranges[id].part;
+        state.ranges[id].status = Status::Done;
+        state.outstanding[part] -= 1;
+        state.failed[part] = true;
+    }
+
+    fn lock_state(&self) -> MutexGuard<State> {
+        self.state
+            .lock()
+            .expect("Failed to acquire scheduler lock, lock poisoned!")
+    }
+}
+
+/// Chunk aligned point roughly halfway through what's left of a range, if at least two chunks
+/// are left.
+fn split_point(cursor: u64, end: u64, chunk_size: u64) -> Option<u64> {
+    let remaining = (end + 1).saturating_sub(cursor);
+    if remaining < 2 * chunk_size {
+        return None;
+    }
+
+    let split = (cursor + remaining / 2 + chunk_size - 1) / chunk_size * chunk_size;
+    if split > cursor && split <= end {
+        Some(split)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn hands_out_queued_ranges_first() {
+        let scheduler = Scheduler::new(10, 2);
+        scheduler.push(0, 0, 99);
+        scheduler.push(1, 100, 149);
+        assert_eq!(
+            scheduler.next(),
+            Some(Assignment {
+                id: 0,
+                part: 0,
+                start: 0,
+                end: 99
+            })
+        );
+        assert_eq!(
+            scheduler.next(),
+            Some(Assignment {
+                id: 1,
+                part: 1,
+                start: 100,
+                end: 149
+            })
+        );
+    }
+
+    #[test]
+    fn splits_largest_remaining_range() {
+        let scheduler = Scheduler::new(10, 2);
+        scheduler.push(0, 0, 99);
+        scheduler.push(1, 100, 149);
+        scheduler.next();
+        scheduler.next();
+        assert_eq!(scheduler.claim(0, 15), 15);
+
+        // 85 bytes left in part 0, split at the chunk boundary past the halfway point
+        assert_eq!(
+            scheduler.next(),
+            Some(Assignment {
+                id: 2,
+                part: 0,
+                start: 60,
+                end: 99
+            })
+        );
+        assert_eq!(scheduler.claim(0, 50), 45);
+        assert!(scheduler.is_complete(0));
+        assert!(!scheduler.finish(0));
+        assert!(scheduler.finish(2));
+    }
+
+    #[test]
+    fn stops_when_nothing_is_worth_splitting() {
+        let scheduler = Scheduler::new(10, 1);
+        scheduler.push(0, 0, 19);
+        scheduler.next();
+        assert_eq!(scheduler.claim(0, 5), 5);
+        assert_eq!(scheduler.next(), None);
+    }
+
+    #[test]
+    fn abandoned_part_is_never_done() {
+        let scheduler = Scheduler::new(10, 1);
+        scheduler.push(0, 0, 99);
+        scheduler.next();
+        scheduler.next();
+        scheduler.abandon(1);
+        assert!(!scheduler.finish(0));
+    }
+
+    #[test]
+    fn resumes_from_first_missing_chunk() {
+        let scheduler = Scheduler::new(10, 1);
+        scheduler.push(0, 0, 99);
+        scheduler.next();
+        assert_eq!(scheduler.claim(0, 25), 25);
+        assert_eq!(
+            scheduler.resume(0, 20),
+            (
+                5,
+                Some(Assignment {
+                    id: 0,
+                    part: 0,
+                    start: 20,
+                    end: 99
+                })
+            )
+        );
+        assert_eq!(scheduler.claim(0, 80), 80);
+        assert_eq!(scheduler.resume(0, 100), (0, None));
+    }
+
+    #[test]
+    fn split_points() {
+        assert_eq!(split_point(0, 99, 10), Some(50));
+        assert_eq!(split_point(15, 99, 10), Some(60));
+        assert_eq!(split_point(0, 19, 10), Some(10));
+        assert_eq!(split_point(5, 19, 10), None);
+        assert_eq!(split_point(100, 99, 10), None);
+    }
+}
@@ -1,96 +1,185 @@
 use pbr::{MultiBar, Pipe, ProgressBar, Units};
-use std::io::Stdout;
-use std::sync::Mutex;
+use std::fmt;
+use std::io::{self, Stdout, Write};
+use std::sync::{Mutex, MutexGuard};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-lazy_static! {
-    static ref TOTALS: Mutex<Vec<u64>> = Mutex::new(vec![]);
-    static ref PBRS: Mutex<Vec<ProgressBar<Pipe>>> = Mutex::new(vec![]);
+const COUNTER_REFRESH: Duration = Duration::from_millis(200);
+const BYTE_UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+/// What a download draws on the terminal, owned by its `Progress`.
+#[derive(Debug)]
+pub enum Display {
+    Hidden,
+    Bars(Bars),
+    Counter(Counter),
+}
+
+/// An overall progress bar above a bar for each part.
+pub struct Bars {
+    /// The overall bar followed by the part bars.
+    pbrs: Mutex<Vec<ProgressBar<Pipe>>>,
 }
 
-pub fn start_pbr(file_name: &str, lengths: Vec<u64>) {
-    let mut mb = MultiBar::new();
-    mb.println(&format!("Downloading: {}", file_name));
+impl fmt::Debug for Bars {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Bars")
+            .field("len", &self.lock_pbrs().len())
+            .finish()
+    }
+}
 
-    let total_length = lengths.iter().sum();
-    build_global_bar(&mut mb, total_length);
-    mb.println("");
+impl Bars {
+    pub fn start(file_name: &str, lengths: Vec<u64>) -> Bars {
+        let mut mb = MultiBar::new();
+        mb.println(&format!("Downloading: {}", file_name));
 
-    for length in lengths {
-        build_child_bar(&mut mb, length);
+        let total_length = lengths.iter().sum();
+        let mut pbrs = vec![build_bar(&mut mb, total_length, None)];
+        mb.println("");
+
+        for length in lengths {
+            pbrs.push(build_bar(&mut mb, length, Some("Pending... ".to_string())));
+        }
+
+        thread::spawn(move || mb.listen());
+        Bars {
+            pbrs: Mutex::new(pbrs),
+        }
     }
 
-    thread::spawn(move || mb.listen());
-}
+    pub fn setting_up(&self, bar_idx: usize) {
+        self.show_message(bar_idx, "Starting... ");
+    }
 
-pub fn setting_up_bar(bar_idx: usize) {
-    let mut pbrs = PBRS
-        .lock()
-        .expect("Failed to acquire PBRS lock, lock poisoned!");
-    pbrs[bar_idx + 1].message("Starting... ");
-    pbrs[bar_idx + 1].tick();
-}
+    pub fn stalled(&self, bar_idx: usize) {
+        self.show_message(bar_idx, "Stalled, restarting... ");
+    }
 
-pub fn start_bar(bar_idx: usize) {
-    let mut pbrs = PBRS
-        .lock()
-        .expect("Failed to acquire PBRS lock, lock poisoned!");
-    pbrs[bar_idx + 1].message("");
-    pbrs[bar_idx + 1].show_message = false;
-    pbrs[bar_idx + 1].tick();
-}
+    pub fn start_part(&self, bar_idx: usize) {
+        let mut pbrs = self.lock_pbrs();
+        pbrs[bar_idx + 1].message("");
+        pbrs[bar_idx + 1].show_message = false;
+        pbrs[bar_idx + 1].tick();
+    }
 
-pub fn update_bar(bar_idx: usize, progress: u64) {
-    let mut pbrs = PBRS
-        .lock()
-        .expect("Failed to acquire PBRS lock, lock poisoned!");
-    let mut totals = TOTALS
-        .lock()
-        .expect("Failed to acquire TOTALS lock, lock poisoned!");
+    /// Moves a part's bar to `progress` and the overall bar to `total_progress`.
+    pub fn update(&self, bar_idx: usize, progress: u64, total_progress: u64) {
+        let mut pbrs = self.lock_pbrs();
+        pbrs[bar_idx + 1].set(progress);
+        pbrs[0].set(total_progress);
+    }
 
-    pbrs[bar_idx + 1].set(progress);
-    totals[bar_idx] = progress;
+    pub fn succeed(&self, bar_idx: usize) {
+        self.lock_pbrs()[bar_idx + 1].finish_print("Download Complete!");
+    }
 
-    let total_progress = totals.iter().sum();
-    pbrs[0].set(total_progress);
-}
+    pub fn fail(&self, bar_idx: usize) {
+        self.lock_pbrs()[bar_idx + 1].finish_print("Download Failed!");
+    }
 
-pub fn success_global_bar() {
-    finish_bar_with_message(0, "Download Complete!");
+    pub fn complete(&self) {
+        self.lock_pbrs()[0].finish_print("Download Complete!");
+    }
+
+    fn show_message(&self, bar_idx: usize, message: &str) {
+        let mut pbrs = self.lock_pbrs();
+        pbrs[bar_idx + 1].show_message = true;
+        pbrs[bar_idx + 1].message(message);
+        pbrs[bar_idx + 1].tick();
+    }
+
+    fn lock_pbrs(&self) -> MutexGuard<Vec<ProgressBar<Pipe>>> {
+        self.pbrs
+            .lock()
+            .expect("Failed to acquire progress bar lock, lock poisoned!")
+    }
 }
 
-pub fn success_bar(bar_idx: usize) {
-    finish_bar_with_message(bar_idx + 1, "Download Complete!");
+/// Byte counter shown in place of progress bars when the download size is unknown.
+#[derive(Debug)]
+pub struct Counter {
+    state: Mutex<ByteCounter>,
 }
 
-pub fn fail_bar(bar_idx: usize) {
-    finish_bar_with_message(bar_idx + 1, "Download Failed!");
+#[derive(Debug)]
+struct ByteCounter {
+    started: Instant,
+    last_draw: Option<Instant>,
+    bytes: u64,
 }
 
-fn finish_bar_with_message(act_bar: usize, message: &str) {
-    PBRS.lock()
-        .expect("Failed to acquire PBRS lock, lock poisoned!")[act_bar]
-        .finish_print(message);
+impl Counter {
+    pub fn start(file_name: &str) -> Counter {
+        println!("Downloading: {}", file_name);
+        Counter {
+            state: Mutex::new(ByteCounter {
+                started: Instant::now(),
+                last_draw: None,
+                bytes: 0,
+            }),
+        }
+    }
+
+    pub fn update(&self, bytes: u64) {
+        let mut counter = self.lock_state();
+        counter.bytes = bytes;
+        let due = counter
+            .last_draw
+            .map_or(true, |last_draw| last_draw.elapsed() >= COUNTER_REFRESH);
+        if due {
+            draw_counter(&mut counter, "");
+        }
+    }
+
+    pub fn finish(&self, message: &str) {
+        draw_counter(&mut self.lock_state(), message);
+        println!();
+    }
+
+    fn lock_state(&self) -> MutexGuard<ByteCounter> {
+        self.state
+            .lock()
+            .expect("Failed to acquire byte counter lock, lock poisoned!")
+    }
 }
 
-fn build_global_bar(mb: &mut MultiBar<Stdout>, size: u64) {
-    build_bar(mb, size, None);
+fn draw_counter(counter: &mut ByteCounter, message: &str) {
+    let elapsed = counter.started.elapsed();
+    let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    let speed = if seconds > 0.0 {
+        counter.bytes as f64 / seconds
+    } else {
+        0.0
+    };
+
+    print!(
+        "\r{} {}/s {}",
+        format_bytes(counter.bytes as f64),
+        format_bytes(speed),
+        message
+    );
+    let _ = io::stdout().flush();
+    counter.last_draw = Some(Instant::now());
 }
 
-fn build_child_bar(mb: &mut MultiBar<Stdout>, size: u64) {
-    build_bar(mb, size, Some("Pending... ".to_string()));
+fn format_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < BYTE_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
 
-    let mut totals = TOTALS
-        .lock()
-        .expect("Failed to acquire TOTALS lock, lock poisoned!");
-    totals.push(0);
+    if unit == 0 {
+        format!("{} {}", value, BYTE_UNITS[unit])
+    } else {
+        format!("{:.2} {}", value, BYTE_UNITS[unit])
+    }
 }
 
-fn build_bar(mb: &mut MultiBar<Stdout>, size: u64, message: Option<String>) {
-    let mut pbrs = PBRS
-        .lock()
-        .expect("Failed to acquire PBRS lock, lock poisoned!");
+fn build_bar(mb: &mut MultiBar<Stdout>, size: u64, message: Option<String>) -> ProgressBar<Pipe> {
     let mut pb = mb.create_bar(size);
     pb.set_max_refresh_rate(Some(Duration::from_millis(200)));
     pb.tick_format("▏▎▍▌▋▊▉██▉▊▋▌▍▎▏");
@@ -104,5 +193,5 @@ fn build_bar(mb: &mut MultiBar<Stdout>, size: u64, message: Option<String>) {
     }
 
     pb.tick();
-    pbrs.push(pb);
+    pb
 }